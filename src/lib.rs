@@ -3,7 +3,7 @@
 //! # Examples
 //!
 //! ```
-//! use tree_multi_set::TreeMultiSet;
+//! use tree_multiset::TreeMultiSet;
 //!
 //! let mut set = TreeMultiSet::new();
 //!
@@ -29,7 +29,9 @@
 //! The `TreeMultiSet` allows for efficient insertion, removal, and counting of elements, making it suitable for scenarios where elements need to be stored along with their counts.
 //!
 
-use std::collections::BTreeMap;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
 
 /// A data structure representing a multi-set implemented using a BTreeMap.
 /// 
@@ -38,6 +40,7 @@ use std::collections::BTreeMap;
 pub struct TreeMultiSet<T> {
     mp: BTreeMap<T, usize>,
     count: usize,
+    version: u64,
 }
 
 impl<T: std::cmp::Ord + Clone> TreeMultiSet<T> {
@@ -46,13 +49,132 @@ impl<T: std::cmp::Ord + Clone> TreeMultiSet<T> {
         Self {
             mp: BTreeMap::new(),
             count: 0,
+            version: 0,
         }
     }
 
+    /// Constructs a `TreeMultiSet` by merging several iterators, each of which is assumed to yield elements in non-decreasing order.
+    ///
+    /// The inputs are merged in ascending order and each run of equal elements is counted with a single map operation.
+    /// If an input is not sorted the result is still correct, but equal elements are grouped less effectively.
+    /// # Complexity
+    /// O(N log k + n log n), where N is the total number of elements, k the number of iterators and n the number of distinct elements
+    pub fn from_sorted_iters<I>(iters: I) -> Self
+    where I: IntoIterator, I::Item: IntoIterator<Item = T> {
+        use std::cmp::Reverse;
+        use std::collections::BinaryHeap;
+
+        let mut iters: Vec<_> = iters.into_iter().map(IntoIterator::into_iter).collect();
+        let mut heap = BinaryHeap::with_capacity(iters.len());
+        for (i, it) in iters.iter_mut().enumerate() {
+            if let Some(x) = it.next() {
+                heap.push(Reverse((x, i)));
+            }
+        }
+
+        let mut set = Self::new();
+        let mut run: Option<(T, usize)> = None;
+        while let Some(Reverse((x, i))) = heap.pop() {
+            if let Some(y) = iters[i].next() {
+                heap.push(Reverse((y, i)));
+            }
+            match &mut run {
+                Some((k, n)) if *k == x => *n += 1,
+                _ => {
+                    if let Some((k, n)) = run.replace((x, 1)) {
+                        set.add_run(k, n);
+                    }
+                }
+            }
+        }
+        if let Some((k, n)) = run {
+            set.add_run(k, n);
+        }
+
+        set.check_invariants();
+        set
+    }
+
+    /// Reconstructs a `TreeMultiSet` from parallel arrays of elements and counts, as produced by [`TreeMultiSet::to_compressed`].
+    /// Returns `None` if the two arrays have different lengths.
+    /// Elements with a count of zero are skipped, and the counts of repeated elements are summed.
+    /// # Complexity
+    /// O(n log n)
+    pub fn from_compressed(keys: Vec<T>, counts: Vec<u32>) -> Option<Self> {
+        if keys.len() != counts.len() {
+            return None;
+        }
+
+        let mut set = Self::new();
+        for (k, c) in keys.into_iter().zip(counts) {
+            if c == 0 {
+                continue;
+            }
+            *set.mp.entry(k).or_insert(0) += c as usize;
+            set.count += c as usize;
+        }
+        set.check_invariants();
+        Some(set)
+    }
+
+    /// Constructs a `TreeMultiSet` from `(element, count)` pairs, rejecting malformed input instead of silently merging it.
+    /// Returns an error if any count is zero or if an element appears in more than one pair.
+    /// # Complexity
+    /// O(n log n)
+    pub fn try_from_pairs(pairs: Vec<(T, usize)>) -> Result<Self, BuildError> {
+        let mut set = Self::new();
+        for (index, (k, c)) in pairs.into_iter().enumerate() {
+            if c == 0 {
+                return Err(BuildError::ZeroCount { index });
+            }
+            if set.mp.contains_key(&k) {
+                return Err(BuildError::DuplicateKey { index });
+            }
+            set.mp.insert(k, c);
+            set.count += c;
+        }
+        set.check_invariants();
+        Ok(set)
+    }
+
+    /// Constructs a `TreeMultiSet` containing the elements of all given sets, summing their counts.
+    /// # Complexity
+    /// O(M log n), where M is the total number of distinct elements across the inputs
+    pub fn merge_all<'a, I: IntoIterator<Item = &'a Self>>(sets: I) -> Self
+    where T: 'a {
+        let mut res = Self::new();
+        for set in sets {
+            res.add_all(set);
+        }
+        res.check_invariants();
+        res
+    }
+
+    /// Constructs a `TreeMultiSet` containing every element of `set` with a count of `count_each`.
+    /// If `count_each` is zero, the result is empty.
+    /// # Complexity
+    /// O(n)
+    pub fn from_btree_set(set: BTreeSet<T>, count_each: usize) -> Self {
+        if count_each == 0 {
+            return Self::new();
+        }
+
+        let count = set.len() * count_each;
+        let res = Self {
+            mp: set.into_iter().map(|k| (k, count_each)).collect(),
+            count,
+            version: 0,
+        };
+        res.check_invariants();
+        res
+    }
+
     /// Removes all elements from the `TreeMultiSet`, leaving it empty.
     pub fn clear(&mut self) {
+        let changed = !self.mp.is_empty();
         self.mp.clear();
         self.count = 0;
+        self.touch_if(changed);
     }
 
     /// Returns `true` if the `TreeMultiSet` is empty, `false` otherwise.
@@ -65,20 +187,288 @@ impl<T: std::cmp::Ord + Clone> TreeMultiSet<T> {
         self.count
     }
 
+    /// Returns a lightweight snapshot of the `TreeMultiSet` that can later be passed to [`TreeMultiSet::changed_since`].
+    /// # Complexity
+    /// O(1)
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { len: self.count, version: self.version }
+    }
+
+    /// Returns `true` if the `TreeMultiSet` has been mutated since `snap` was taken from it, `false` otherwise.
+    ///
+    /// Every mutation counted by [`TreeMultiSet::mutation_count`] is a change, even if it left the contents as they were.
+    /// A snapshot is only meaningful for the set it was taken from.
+    /// # Complexity
+    /// O(1)
+    pub fn changed_since(&self, snap: &Snapshot) -> bool {
+        self.version != snap.version || self.count != snap.len
+    }
+
+    /// Returns the number of mutations the `TreeMultiSet` has undergone since it was constructed. Every constructor starts at zero.
+    ///
+    /// A call to a mutating method counts as exactly one mutation if it inserted or removed at least one occurrence,
+    /// regardless of how many elements it affects, so `clear` and bulk operations such as `retain_range` or `ingest_sorted` count once.
+    /// This holds even if the contents end up as they were, as with `slide(x, &x)`.
+    /// A call that inserts and removes nothing, such as removing an absent element or clearing an empty set, is not counted.
+    /// The counter wraps around on overflow.
+    /// # Complexity
+    /// O(1)
+    pub fn mutation_count(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns a reference to the underlying map from each distinct element to its count (multiplicity).
+    /// The map is read-only, so the total count of the `TreeMultiSet` always stays consistent.
+    pub fn counts(&self) -> &BTreeMap<T, usize> {
+        &self.mp
+    }
+
+    /// Returns an iterator over the distinct elements and their counts as `(&element, count)` pairs, in descending order.
+    pub fn iter_counts_rev(&self) -> impl Iterator<Item = (&T, usize)> {
+        self.mp.iter().rev().map(|(k, &v)| (k, v))
+    }
+
+    /// Returns a rough estimate of the memory used by the `TreeMultiSet`, in bytes.
+    ///
+    /// The estimate assumes that each distinct element is stored once alongside a `usize` count,
+    /// in densely packed BTree nodes of 11 entries with a small fixed header each.
+    /// Memory owned by the elements themselves (such as the contents of a `String`) is not included.
+    // `usize::div_ceil` would require Rust 1.73.
+    #[allow(clippy::manual_div_ceil)]
+    pub fn estimated_size_bytes(&self) -> usize {
+        const NODE_CAPACITY: usize = 11;
+        const NODE_HEADER_BYTES: usize = 16;
+
+        let entry_bytes = std::mem::size_of::<T>() + std::mem::size_of::<usize>();
+        let nodes = (self.mp.len() + NODE_CAPACITY - 1) / NODE_CAPACITY;
+        std::mem::size_of::<Self>() + nodes * (NODE_HEADER_BYTES + NODE_CAPACITY * entry_bytes)
+    }
+
+    /// Returns `true` if the `TreeMultiSet` contains at least `n` elements, counted with multiplicity.
+    /// # Complexity
+    /// O(1)
+    pub fn has_at_least_total(&self, n: usize) -> bool {
+        self.count >= n
+    }
+
+    /// Returns `true` if the `TreeMultiSet` contains at least `n` distinct elements.
+    /// # Complexity
+    /// O(1)
+    pub fn has_at_least_distinct(&self, n: usize) -> bool {
+        self.mp.len() >= n
+    }
+
     /// Returns the count of occurrences of a specified element in the `TreeMultiSet`.
+    ///
+    /// The element may be any borrowed form of `T`, as with `BTreeMap::get`.
     /// # Complexity
     /// O(log n)
-    pub fn count(&self, k: &T) -> usize {
+    pub fn count<Q>(&self, k: &Q) -> usize
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
         *self.mp.get(k).unwrap_or(&0)
     }
 
+    /// Returns the count of occurrences of each queried element, in the order they are given.
+    /// Elements that are not present have a count of zero.
+    /// # Complexity
+    /// O(m log n), where m is the number of queried elements
+    pub fn counts_of<'a, I: IntoIterator<Item = &'a T>>(&self, keys: I) -> Vec<usize>
+    where T: 'a {
+        keys.into_iter().map(|k| self.count(k)).collect()
+    }
+
+    /// Returns a guard giving mutable access to the count of a specified element, using `default` if the element is not present.
+    ///
+    /// The total count of the `TreeMultiSet` is reconciled when the guard is dropped,
+    /// and the element is removed if its count has been set to zero, so the set stays consistent however the count is changed.
+    /// # Complexity
+    /// O(log n)
+    pub fn count_mut_or_insert(&mut self, k: T, default: usize) -> CountMut<'_, T> {
+        let old = self.count(&k);
+        let value = if old == 0 { default } else { old };
+        CountMut { set: self, key: Some(k), old, value }
+    }
+
+    /// Returns the count of occurrences of a specified element, capped at `cap`.
+    /// # Complexity
+    /// O(log n)
+    pub fn count_capped<Q>(&self, k: &Q, cap: usize) -> usize
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        self.count(k).min(cap)
+    }
+
     /// Returns `true` if the `TreeMultiSet` contains a specified element, `false` otherwise.
+    ///
+    /// The element may be any borrowed form of `T`, as with `BTreeMap::contains_key`.
     /// # Complexity
     /// O(log n)
-    pub fn contains(&self, k: &T) -> bool {
+    pub fn contains<Q>(&self, k: &Q) -> bool
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
         self.mp.contains_key(k)
     }
 
+    /// Returns `true` if every element yielded by `keys` is contained in the `TreeMultiSet`, `false` otherwise.
+    /// Stops at the first element that is not present.
+    /// # Complexity
+    /// O(m log n), where m is the number of queried elements
+    pub fn contains_all<I: IntoIterator<Item = T>>(&self, keys: I) -> bool {
+        keys.into_iter().all(|k| self.contains(&k))
+    }
+
+    /// Returns `true` if at least one element yielded by `keys` is contained in the `TreeMultiSet`, `false` otherwise.
+    /// Stops at the first element that is present.
+    /// # Complexity
+    /// O(m log n), where m is the number of queried elements
+    pub fn contains_any<I: IntoIterator<Item = T>>(&self, keys: I) -> bool {
+        keys.into_iter().any(|k| self.contains(&k))
+    }
+
+    /// Returns the `k` elements with the highest counts as `(element, count)` pairs,
+    /// sorted by count in descending order and then by element in ascending order.
+    /// If `k` exceeds the number of distinct elements, all of them are returned.
+    /// # Complexity
+    /// O(n log k)
+    pub fn top_k_frequent(&self, k: usize) -> Vec<(&T, usize)> {
+        use std::cmp::Reverse;
+        use std::collections::BinaryHeap;
+
+        if k == 0 {
+            return Vec::new();
+        }
+
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        for (key, &v) in self.mp.iter() {
+            heap.push(Reverse((v, Reverse(key))));
+            if heap.len() > k {
+                heap.pop();
+            }
+        }
+
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((v, Reverse(key)))| (key, v))
+            .collect()
+    }
+
+    /// Returns the half-open range of positions `(start, end)` occupied by a specified element in ascending order,
+    /// so that `end - start` is its count. If the element is not present, both bounds are the position where it would be inserted.
+    /// # Complexity
+    /// O(n)
+    pub fn equal_range<Q>(&self, k: &Q) -> (usize, usize)
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        let start = self.mp.range((Bound::Unbounded, Bound::Excluded(k))).map(|(_, &v)| v).sum::<usize>();
+        (start, start + self.count(k))
+    }
+
+    /// Returns the fraction of elements, counted with multiplicity, that are less than or equal to `k`,
+    /// i.e. the empirical cumulative distribution function evaluated at `k`. Returns `0.0` if the `TreeMultiSet` is empty.
+    /// # Complexity
+    /// O(n)
+    pub fn quantile_rank<Q>(&self, k: &Q) -> f64
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        if self.is_empty() {
+            return 0.0;
+        }
+        self.equal_range(k).1 as f64 / self.count as f64
+    }
+
+    /// Returns `(count_lt, count_le)`: the number of elements less than `k` and less than or equal to `k`,
+    /// which are the positions Python's `bisect_left` and `bisect_right` would return on the flattened elements.
+    /// Both bounds come from a single walk, as with [`TreeMultiSet::equal_range`].
+    /// # Complexity
+    /// O(n)
+    pub fn bisect<Q>(&self, k: &Q) -> (usize, usize)
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        self.equal_range(k)
+    }
+
+    /// Returns a reference to the element closest to `k`, or `None` if the `TreeMultiSet` is empty.
+    /// If two elements are equally close, the smaller one is returned.
+    /// A distance that does not fit in `T` is larger than any that does, so the query is safe at the extremes of signed types.
+    /// # Complexity
+    /// O(log n)
+    pub fn nearest(&self, k: &T) -> Option<&T>
+    where T: Copy + CheckedSub {
+        let lo = self.mp.range(..=k).next_back().map(|(x, _)| x);
+        let hi = self.mp.range(k..).next().map(|(x, _)| x);
+        match (lo, hi) {
+            (Some(lo), Some(hi)) => match (hi.checked_sub(*k), k.checked_sub(*lo)) {
+                (Some(above), Some(below)) if above < below => Some(hi),
+                (Some(_), None) => Some(hi),
+                _ => Some(lo),
+            },
+            (lo, hi) => lo.or(hi),
+        }
+    }
+
+    /// Searches for a specified element in ascending order, mirroring `slice::binary_search`.
+    /// Returns `Ok` with the position of its first occurrence if it is present,
+    /// or `Err` with the position where it would be inserted otherwise.
+    /// # Complexity
+    /// O(n)
+    pub fn search<Q>(&self, k: &Q) -> Result<usize, usize>
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        let (start, end) = self.equal_range(k);
+        if start < end { Ok(start) } else { Err(start) }
+    }
+
+    /// Returns `true` if a specified element occurs exactly `n` times, `false` otherwise.
+    /// An `n` of zero checks that the element is absent.
+    /// # Complexity
+    /// O(log n)
+    pub fn contains_exactly<Q>(&self, k: &Q, n: usize) -> bool
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        self.count(k) == n
+    }
+
+    /// Returns the position of the first occurrence of a specified element in ascending order, or `None` if it is not present.
+    /// # Complexity
+    /// O(n)
+    pub fn first_rank<Q>(&self, k: &Q) -> Option<usize>
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        self.search(k).ok()
+    }
+
+    /// Returns the position in ascending order of the `occurrence`-th (0-based) copy of a specified element,
+    /// or `None` if the element has `occurrence` or fewer copies.
+    /// # Complexity
+    /// O(n)
+    pub fn rank_of_occurrence<Q>(&self, k: &Q, occurrence: usize) -> Option<usize>
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        let (start, end) = self.equal_range(k);
+        (occurrence < end - start).then_some(start + occurrence)
+    }
+
+    /// Returns the position of the last occurrence of a specified element in ascending order, or `None` if it is not present.
+    /// # Complexity
+    /// O(n)
+    pub fn last_rank<Q>(&self, k: &Q) -> Option<usize>
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        let (start, end) = self.equal_range(k);
+        if start < end { Some(end - 1) } else { None }
+    }
+
+    /// Returns, for each queried value, the number of elements strictly less than it, which is the position of its first occurrence
+    /// or, if it is absent, the position where it would be inserted.
+    /// The queries are answered in a single sweep over the set after sorting them.
+    /// # Complexity
+    /// O(m log m + n), where m is the number of queried values
+    pub fn rank_of_value_sequence(&self, values: &[T]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..values.len()).collect();
+        order.sort_by(|&a, &b| values[a].cmp(&values[b]));
+
+        let mut res = vec![0; values.len()];
+        let mut entries = self.mp.iter().peekable();
+        let mut acc = 0;
+        for i in order {
+            while let Some((_, &v)) = entries.next_if(|(k, _)| *k < &values[i]) {
+                acc += v;
+            }
+            res[i] = acc;
+        }
+        res
+    }
+
     /// Returns an immutable reference to the first (minimum) element in the `TreeMultiSet`, or `None` if it is empty.
     /// # Complexity
     /// O(log n)
@@ -93,6 +483,36 @@ impl<T: std::cmp::Ord + Clone> TreeMultiSet<T> {
         self.mp.last_key_value().map(|(k, _)| k)
     }
 
+    /// Returns immutable references to the first (minimum) and last (maximum) elements in the `TreeMultiSet`, or `None` if it is empty.
+    /// # Complexity
+    /// O(log n)
+    pub fn min_max(&self) -> Option<(&T, &T)> {
+        Some((self.first()?, self.last()?))
+    }
+
+    /// Returns the difference between the last (maximum) and first (minimum) elements, or `None` if the `TreeMultiSet` is empty.
+    /// # Complexity
+    /// O(log n)
+    pub fn span(&self) -> Option<<T as std::ops::Sub>::Output>
+    where T: std::ops::Sub {
+        let (min, max) = self.min_max()?;
+        Some(max.clone() - min.clone())
+    }
+
+    /// Returns the count of occurrences of the first (minimum) element, or zero if the `TreeMultiSet` is empty.
+    /// # Complexity
+    /// O(log n)
+    pub fn count_of_min(&self) -> usize {
+        self.mp.first_key_value().map_or(0, |(_, &v)| v)
+    }
+
+    /// Returns the count of occurrences of the last (maximum) element, or zero if the `TreeMultiSet` is empty.
+    /// # Complexity
+    /// O(log n)
+    pub fn count_of_max(&self) -> usize {
+        self.mp.last_key_value().map_or(0, |(_, &v)| v)
+    }
+
     /// Removes one occurrence of the first (minimum) element in the `TreeMultiSet` and returns it, or `None` if the set is empty.
     /// # Complexity
     /// O(log n)
@@ -109,186 +529,3696 @@ impl<T: std::cmp::Ord + Clone> TreeMultiSet<T> {
         self.remove_one(&last_key)
     }
 
+    /// Removes all occurrences of the element with the smallest count and returns it together with its count,
+    /// or `None` if the set is empty. Ties are broken by the smallest element.
+    /// # Complexity
+    /// O(n)
+    pub fn remove_least_frequent(&mut self) -> Option<(T, usize)> {
+        let key = self.mp.iter().min_by_key(|(_, &v)| v).map(|(k, _)| k.clone())?;
+        let (k, v) = self.mp.remove_entry(&key)?;
+        self.count -= v;
+        self.touch();
+        Some((k, v))
+    }
+
+    /// Removes all occurrences of the element with the largest count and returns it together with its count,
+    /// or `None` if the set is empty. Ties are broken by the smallest element.
+    /// # Complexity
+    /// O(n)
+    pub fn remove_most_frequent(&mut self) -> Option<(T, usize)> {
+        let key = self.mp.iter()
+            .max_by(|(ka, va), (kb, vb)| va.cmp(vb).then_with(|| kb.cmp(ka)))
+            .map(|(k, _)| k.clone())?;
+        let (k, v) = self.mp.remove_entry(&key)?;
+        self.count -= v;
+        self.touch();
+        Some((k, v))
+    }
+
+    /// Removes one occurrence of the first (minimum) element and returns it together with the number of its occurrences remaining,
+    /// or `None` if the set is empty.
+    /// # Complexity
+    /// O(log n)
+    pub fn pop_first_with_count(&mut self) -> Option<(T, usize)> {
+        let mut e = self.mp.first_entry()?;
+        *e.get_mut() -= 1;
+        self.count -= 1;
+        let remaining = *e.get();
+        let k = if remaining == 0 { e.remove_entry().0 } else { e.key().clone() };
+        self.touch();
+        Some((k, remaining))
+    }
+
+    /// Removes one occurrence of the last (maximum) element and returns it together with the number of its occurrences remaining,
+    /// or `None` if the set is empty.
+    /// # Complexity
+    /// O(log n)
+    pub fn pop_last_with_count(&mut self) -> Option<(T, usize)> {
+        let mut e = self.mp.last_entry()?;
+        *e.get_mut() -= 1;
+        self.count -= 1;
+        let remaining = *e.get();
+        let k = if remaining == 0 { e.remove_entry().0 } else { e.key().clone() };
+        self.touch();
+        Some((k, remaining))
+    }
+
+    /// Removes the element at a specified position in ascending order and returns it, or `None` if `index` is out of range.
+    /// # Complexity
+    /// O(n), since the position is found by walking the counts of the distinct elements
+    pub fn remove_nth(&mut self, index: usize) -> Option<T> {
+        let k = self.nth_entry(index)?.0.clone();
+        self.remove_one(&k)
+    }
+
+    /// Returns the `index`-th distinct element in ascending order, ignoring multiplicity, or `None` if `index` is out of range.
+    /// # Complexity
+    /// O(index), since `BTreeMap` does not support positional access
+    pub fn nth_distinct(&self, index: usize) -> Option<&T> {
+        self.mp.keys().nth(index)
+    }
+
+    /// Returns the number of elements equal to the element at a specified position in ascending order, or 0 if `rank` is out of range.
+    /// # Complexity
+    /// O(n), since the position is found by walking the counts of the distinct elements
+    pub fn count_tied_at_rank(&self, rank: usize) -> usize {
+        self.nth_entry(rank).map_or(0, |(_, v)| v)
+    }
+
+    /// Returns the element at a specified position in ascending order together with its count, or `None` if `index` is out of range.
+    fn nth_entry(&self, index: usize) -> Option<(&T, usize)> {
+        if index >= self.count {
+            return None;
+        }
+
+        let mut idx = index;
+        for (k, &v) in self.mp.iter() {
+            if idx < v {
+                return Some((k, v));
+            }
+            idx -= v;
+        }
+        None
+    }
+
+    /// Inserts `incoming` and removes one occurrence of `outgoing`, as when sliding a fixed-size window over a stream.
+    /// Returns `true` if `outgoing` was present, in which case `len()` is unchanged;
+    /// otherwise only the insertion happens and `len()` grows by one.
+    /// # Complexity
+    /// O(log n)
+    pub fn slide<Q>(&mut self, incoming: T, outgoing: &Q) -> bool
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        *self.mp.entry(incoming).or_insert(0) += 1;
+        self.count += 1;
+        let removed = match self.mp.get_mut(outgoing) {
+            Some(v) => {
+                *v -= 1;
+                if *v == 0 {
+                    self.mp.remove(outgoing);
+                }
+                self.count -= 1;
+                true
+            }
+            None => false,
+        };
+        self.touch();
+        removed
+    }
+
+    /// Removes one occurrence of the smallest element within a specified range and returns it,
+    /// or `None` if the range contains no elements.
+    /// # Complexity
+    /// O(log n)
+    pub fn remove_one_in_range<R>(&mut self, rng: R) -> Option<T>
+    where R: std::ops::RangeBounds<T> {
+        let k = self.mp.range(rng).next()?.0.clone();
+        self.remove_one(&k)
+    }
+
+    /// Removes one occurrence of the largest element within a specified range and returns it,
+    /// or `None` if the range contains no elements.
+    /// # Complexity
+    /// O(log n)
+    pub fn remove_last_in_range<R>(&mut self, rng: R) -> Option<T>
+    where R: std::ops::RangeBounds<T> {
+        let k = self.mp.range(rng).next_back()?.0.clone();
+        self.remove_one(&k)
+    }
+
+    /// Repeatedly removes the first (minimum) element while it satisfies `pred`, and returns the removed elements in ascending order.
+    /// Stops at the first minimum that does not satisfy `pred`. The predicate is called once per distinct element.
+    /// # Complexity
+    /// O(k log n + m), where k is the number of distinct elements removed and m the number of elements removed
+    pub fn pop_first_while<F: FnMut(&T) -> bool>(&mut self, mut pred: F) -> Vec<T> {
+        let mut res = Vec::new();
+        while let Some(e) = self.mp.first_entry() {
+            if !pred(e.key()) {
+                break;
+            }
+            let (k, v) = e.remove_entry();
+            self.count -= v;
+            res.resize(res.len() + v, k);
+        }
+        self.touch_if(!res.is_empty());
+        res
+    }
+
+    /// Repeatedly removes the last (maximum) element while it satisfies `pred`, and returns the removed elements in descending order.
+    /// Stops at the first maximum that does not satisfy `pred`. The predicate is called once per distinct element.
+    /// # Complexity
+    /// O(k log n + m), where k is the number of distinct elements removed and m the number of elements removed
+    pub fn pop_last_while<F: FnMut(&T) -> bool>(&mut self, mut pred: F) -> Vec<T> {
+        let mut res = Vec::new();
+        while let Some(e) = self.mp.last_entry() {
+            if !pred(e.key()) {
+                break;
+            }
+            let (k, v) = e.remove_entry();
+            self.count -= v;
+            res.resize(res.len() + v, k);
+        }
+        self.touch_if(!res.is_empty());
+        res
+    }
+
     /// Inserts an element into the `TreeMultiSet`.
     /// # Complexity
     /// O(log n)
     pub fn insert(&mut self, k: T) {
         self.count += 1;
         *self.mp.entry(k).or_insert(0) += 1;
+        self.touch();
+    }
+
+    /// Inserts an element into the `TreeMultiSet` by reference.
+    /// If the element is already present its count is incremented in place, so the key is only cloned when it is new.
+    /// # Complexity
+    /// O(log n)
+    pub fn insert_ref(&mut self, k: &T) {
+        self.count += 1;
+        if let Some(v) = self.mp.get_mut(k) {
+            *v += 1;
+        } else {
+            self.mp.insert(k.clone(), 1);
+        }
+        self.touch();
+    }
+
+    /// Inserts `n` occurrences of an element into the `TreeMultiSet` and returns its new count.
+    /// Returns `Err(CountOverflow)` without modifying the set if the count of the element or the total count would overflow `usize`.
+    /// # Complexity
+    /// O(log n)
+    pub fn try_insert_n(&mut self, k: T, n: usize) -> Result<usize, CountOverflow> {
+        let total = self.count.checked_add(n).ok_or(CountOverflow)?;
+        let current = self.count(&k);
+        let new = current.checked_add(n).ok_or(CountOverflow)?;
+        if n == 0 {
+            return Ok(current);
+        }
+
+        self.mp.insert(k, new);
+        self.count = total;
+        self.touch();
+        Ok(new)
+    }
+
+    /// Inserts every element of `other` into the `TreeMultiSet`, summing counts, while leaving `other` unchanged.
+    /// Keys are only cloned when they are not already present.
+    /// # Complexity
+    /// O(m log n), where m is the number of distinct elements in `other`
+    pub fn insert_all_from(&mut self, other: &Self) {
+        self.add_all(other);
+        self.touch_if(!other.is_empty());
+    }
+
+    /// Adds every element of `other` without recording a mutation, cloning keys only when they are new.
+    fn add_all(&mut self, other: &Self) {
+        for (k, &v) in other.mp.iter() {
+            if let Some(c) = self.mp.get_mut(k) {
+                *c += v;
+            } else {
+                self.mp.insert(k.clone(), v);
+            }
+        }
+        self.count += other.count;
+    }
+
+    /// Inserts `n` occurrences of an element into the `TreeMultiSet` with a single map operation,
+    /// which is the efficient way to add a run of equal values. Use [`TreeMultiSet::try_insert_n`] to detect count overflow.
+    /// # Complexity
+    /// O(log n)
+    pub fn insert_run(&mut self, k: T, n: usize) {
+        if n == 0 {
+            return;
+        }
+        self.add_run(k, n);
+        self.touch();
+    }
+
+    /// Adds `n` occurrences of an element without recording a mutation, for callers that call `touch` or `check_invariants` once at the end.
+    fn add_run(&mut self, k: T, n: usize) {
+        *self.mp.entry(k).or_insert(0) += n;
+        self.count += n;
+    }
+
+    /// Inserts every element yielded by `iter`, adding each run of adjacent equal elements with a single map operation, as [`TreeMultiSet::insert_run`] does.
+    /// The input is expected to be sorted, which groups all equal elements together; unsorted input is still handled correctly.
+    /// # Complexity
+    /// O(m + r log n), where m is the number of elements and r the number of runs of adjacent equal elements
+    pub fn ingest_sorted<I: Iterator<Item = T>>(&mut self, iter: I) {
+        let before = self.count;
+        let mut run: Option<(T, usize)> = None;
+        for x in iter {
+            match &mut run {
+                Some((k, n)) if *k == x => *n += 1,
+                _ => {
+                    if let Some((k, n)) = run.replace((x, 1)) {
+                        self.add_run(k, n);
+                    }
+                }
+            }
+        }
+        if let Some((k, n)) = run {
+            self.add_run(k, n);
+        }
+        self.touch_if(self.count != before);
     }
 
     /// Removes one occurrence of a specified element from the `TreeMultiSet` and returns it, or `None` if the element is not present.
+    ///
+    /// The element may be any borrowed form of `T`, as with `BTreeMap::remove`.
     /// # Complexity
     /// O(log n)
-    pub fn remove_one(&mut self, k: &T) -> Option<T> {
-        let Some(v) = self.mp.get_mut(k) else { return None; };
+    pub fn remove_one<Q>(&mut self, k: &Q) -> Option<T>
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        let v = self.mp.get_mut(k)?;
         *v -= 1;
         self.count -= 1;
         if *v == 0 {
-            self.mp.remove(k);
+            let key = self.mp.remove_entry(k).map(|(key, _)| key);
+            self.touch();
+            return key;
         }
 
-        Some(k.clone())
+        self.touch();
+        self.mp.get_key_value(k).map(|(key, _)| key.clone())
     }
 
     /// Removes all occurrences of a specified element from the `TreeMultiSet` and returns it, or `None` if the element is not present.
+    ///
+    /// The element may be any borrowed form of `T`, as with `BTreeMap::remove`.
     /// # Complexity
     /// O(log n)
-    pub fn remove_all(&mut self, k: &T) -> Option<T>{
-        if let Some(v) = self.mp.get_mut(k) {
-            self.count -= *v;
-            *v = 0;
-            self.mp.remove(k);
-            return Some(k.clone());
+    pub fn remove_all<Q>(&mut self, k: &Q) -> Option<T>
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        let (key, v) = self.mp.remove_entry(k)?;
+        self.count -= v;
+        self.touch();
+        Some(key)
+    }
+
+    /// Sets the count of every element in the `TreeMultiSet` to exactly `n`.
+    /// If `n` is zero, the `TreeMultiSet` is cleared.
+    /// # Complexity
+    /// O(n)
+    pub fn set_all_counts(&mut self, n: usize) {
+        if n == 0 {
+            self.clear();
+            return;
         }
-        None
+        let mut changed = false;
+        for v in self.mp.values_mut() {
+            changed |= *v != n;
+            *v = n;
+        }
+        self.count = self.mp.len() * n;
+        self.touch_if(changed);
     }
 
-    /// Returns an iterator over the elements of the `TreeMultiSet`.
-    pub fn iter(&self) -> impl Iterator<Item = &T> {
-        self.mp.iter().flat_map(|(k , &v)| (0..v).map(move |_| k))
+    /// Caps the count of every element in the `TreeMultiSet` at `max`, reducing any count that exceeds it.
+    /// If `max` is zero, the `TreeMultiSet` is cleared.
+    /// # Complexity
+    /// O(n)
+    pub fn cap_counts(&mut self, max: usize) {
+        if max == 0 {
+            self.clear();
+            return;
+        }
+        let before = self.count;
+        for v in self.mp.values_mut() {
+            if *v > max {
+                self.count -= *v - max;
+                *v = max;
+            }
+        }
+        self.touch_if(self.count != before);
     }
 
-    /// Returns an iterator over the elements of the `TreeMultiSet` within a specified range.
-    pub fn range<R>(&self, rng: R) -> impl Iterator<Item = &T> + DoubleEndedIterator
+    /// Splits the `TreeMultiSet` into two at the given position in ascending order.
+    /// Returns a new `TreeMultiSet` containing the elements at positions `>= rank`, leaving the first `rank` elements in `self`.
+    /// The occurrences of an element may be divided between the two sets.
+    /// # Complexity
+    /// O(n)
+    pub fn split_at_rank(&mut self, rank: usize) -> Self {
+        if rank >= self.count {
+            return Self::new();
+        }
+
+        let mut acc = 0;
+        let mut split = None;
+        for (k, &v) in self.mp.iter() {
+            if acc + v > rank {
+                split = Some((k.clone(), rank - acc));
+                break;
+            }
+            acc += v;
+        }
+        let (key, keep) = split.expect("rank is less than len");
+
+        let mut mp = self.mp.split_off(&key);
+        if keep > 0 {
+            *mp.get_mut(&key).expect("split key is present") -= keep;
+            self.mp.insert(key, keep);
+        }
+
+        let moved = self.count - rank;
+        self.count = rank;
+        self.touch();
+        let res = Self { mp, count: moved, version: 0 };
+        res.check_invariants();
+        res
+    }
+
+    /// Retains only the elements within a specified range, removing all others from the `TreeMultiSet`.
+    /// # Complexity
+    /// O(n)
+    pub fn retain_range<R>(&mut self, rng: R)
     where R: std::ops::RangeBounds<T> {
-        self.mp.range(rng).flat_map(|(k , &v)| (0..v).map(move |_| k))
+        let before = self.count;
+        self.mp.retain(|k, _| rng.contains(k));
+        self.count = self.mp.values().sum();
+        self.touch_if(self.count != before);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Draws `n` elements at random without replacement, treating every occurrence as a separate member of the population,
+    /// so each element is drawn with probability proportional to its count. The drawn elements are returned in ascending order.
+    /// If `n` exceeds the number of elements, all elements are returned.
+    ///
+    /// Requires the `rand` feature.
+    /// # Complexity
+    /// O(n log n + m), where m is the number of distinct elements
+    #[cfg(feature = "rand")]
+    pub fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R, n: usize) -> Vec<T> {
+        let n = n.min(self.count);
+        let mut positions = rand::seq::index::sample(rng, self.count, n).into_vec();
+        positions.sort_unstable();
+
+        let mut res = Vec::with_capacity(n);
+        let mut positions = positions.into_iter().peekable();
+        let mut acc = 0;
+        for (k, &v) in self.mp.iter() {
+            acc += v;
+            while positions.next_if(|&p| p < acc).is_some() {
+                res.push(k.clone());
+            }
+            if positions.peek().is_none() {
+                break;
+            }
+        }
+
+        res
+    }
+
+    /// Returns a reference to one element chosen at random with probability proportional to its count, or `None` if the set is empty.
+    ///
+    /// Requires the `rand` feature.
+    /// # Complexity
+    /// O(n)
+    #[cfg(feature = "rand")]
+    pub fn choose<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> Option<&T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut idx = rng.gen_range(0..self.count);
+        for (k, &v) in self.mp.iter() {
+            if idx < v {
+                return Some(k);
+            }
+            idx -= v;
+        }
+        unreachable!("index is less than len")
+    }
+
+    /// Keeps every `step`-th distinct element in ascending order, starting from the minimum, and removes all occurrences of the others.
+    /// A `step` of 1 leaves the `TreeMultiSet` unchanged.
+    /// # Panics
+    /// Panics if `step` is zero.
+    /// # Complexity
+    /// O(n)
+    pub fn keep_every_nth_distinct(&mut self, step: usize) {
+        assert!(step != 0, "step must be non-zero");
+        if step == 1 {
+            return;
+        }
+
+        let mut idx = 0;
+        let mut removed = 0;
+        self.mp.retain(|_, v| {
+            let keep = idx % step == 0;
+            idx += 1;
+            if !keep {
+                removed += *v;
+            }
+            keep
+        });
+        self.count -= removed;
+        self.touch_if(removed > 0);
+    }
+
+    /// Returns the signed change `other.count(k) - self.count(k)` for every element whose count differs between the two sets,
+    /// sorted by element. Elements with equal counts are omitted.
+    /// A change that does not fit in `i64` saturates at `i64::MAX` or `i64::MIN`.
+    /// # Complexity
+    /// O(n + m)
+    pub fn diff(&self, other: &Self) -> Vec<(T, i64)> {
+        self.merge_walk(other)
+            .filter(|&(_, a, b)| a != b)
+            .map(|(k, a, b)| {
+                let d = if b > a {
+                    i64::try_from(b - a).unwrap_or(i64::MAX)
+                } else {
+                    i64::try_from(a - b).map_or(i64::MIN, |d| -d)
+                };
+                (k.clone(), d)
+            })
+            .collect()
+    }
+
+    /// Returns a new `TreeMultiSet` in which the count of each element is `resolver(k, self.count(k), other.count(k))`,
+    /// for every element present in either set. Elements whose resolved count is zero are dropped.
+    /// # Complexity
+    /// O((n + m) log(n + m))
+    pub fn merge_with<F: FnMut(&T, usize, usize) -> usize>(&self, other: &Self, mut resolver: F) -> Self {
+        let mut res = Self::new();
+        for (k, a, b) in self.merge_walk(other) {
+            let v = resolver(k, a, b);
+            if v > 0 {
+                res.mp.insert(k.clone(), v);
+                res.count += v;
+            }
+        }
+        res.check_invariants();
+        res
+    }
+
+    /// Returns a new `TreeMultiSet` in which the count of each element is `self.count(k) * self_weight + other.count(k) * other_weight`.
+    /// An operand with weight zero contributes nothing, and elements whose weighted count is zero are dropped.
+    /// # Panics
+    /// Panics if any weighted count, or the weighted total, overflows `usize`.
+    /// # Complexity
+    /// O((n + m) log(n + m))
+    pub fn weighted_merge(&self, self_weight: usize, other: &Self, other_weight: usize) -> Self {
+        let mut res = Self::new();
+        for (k, a, b) in self.merge_walk(other) {
+            let v = a.checked_mul(self_weight)
+                .zip(b.checked_mul(other_weight))
+                .and_then(|(x, y)| x.checked_add(y))
+                .expect("count overflowed usize");
+            if v > 0 {
+                res.mp.insert(k.clone(), v);
+                res.count = res.count.checked_add(v).expect("count overflowed usize");
+            }
+        }
+        res.check_invariants();
+        res
+    }
+
+    /// Returns the distinct elements present in `self` but absent from `other`, ignoring counts, in ascending order.
+    /// # Complexity
+    /// O(n + m)
+    pub fn keys_only_in_self<'a>(&'a self, other: &'a Self) -> Vec<&'a T> {
+        self.merge_walk(other)
+            .filter(|&(_, a, b)| a > 0 && b == 0)
+            .map(|(k, _, _)| k)
+            .collect()
+    }
+
+    /// Walks both maps in lockstep, yielding every element present in either set together with its count in `self` and in `other`.
+    fn merge_walk<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = (&'a T, usize, usize)> {
+        use std::cmp::Ordering;
+
+        let mut a = self.mp.iter().peekable();
+        let mut b = other.mp.iter().peekable();
+        std::iter::from_fn(move || {
+            let ord = match (a.peek(), b.peek()) {
+                (Some((ka, _)), Some((kb, _))) => ka.cmp(kb),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => return None,
+            };
+            match ord {
+                Ordering::Less => a.next().map(|(k, &v)| (k, v, 0)),
+                Ordering::Greater => b.next().map(|(k, &v)| (k, 0, v)),
+                Ordering::Equal => {
+                    let (k, &va) = a.next()?;
+                    let (_, &vb) = b.next()?;
+                    Some((k, va, vb))
+                }
+            }
+        })
+    }
+
+    /// Adjusts the count of each given element by a signed delta, such as the output of [`TreeMultiSet::diff`].
+    /// A count that would drop below zero is clamped to zero, and elements whose count reaches zero are removed.
+    /// A delta whose magnitude does not fit in `usize` is treated as `usize::MAX`.
+    /// # Complexity
+    /// O(m log n), where m is the number of deltas
+    pub fn apply_diff<I: IntoIterator<Item = (T, i64)>>(&mut self, deltas: I) {
+        let mut changed = false;
+        for (k, d) in deltas {
+            let d_abs = usize::try_from(d.unsigned_abs()).unwrap_or(usize::MAX);
+            if d > 0 {
+                *self.mp.entry(k).or_insert(0) += d_abs;
+                self.count += d_abs;
+                changed = true;
+            } else if let Some(v) = self.mp.get_mut(&k) {
+                let dec = d_abs.min(*v);
+                *v -= dec;
+                self.count -= dec;
+                changed |= dec > 0;
+                if *v == 0 {
+                    self.mp.remove(&k);
+                }
+            }
+        }
+        self.touch_if(changed);
+    }
+
+    /// Returns `(distinct, total)` for the elements within a specified range,
+    /// where `distinct` is the number of distinct elements and `total` is the number of elements counted with multiplicity.
+    /// # Complexity
+    /// O(log n + k), where k is the number of distinct elements in the range
+    pub fn count_range_of<R>(&self, rng: R) -> (usize, usize)
+    where R: std::ops::RangeBounds<T> {
+        self.mp.range(rng).fold((0, 0), |(distinct, total), (_, &v)| (distinct + 1, total + v))
+    }
+
+    /// Returns the number of distinct elements within the inclusive range `[lo, hi]`. Returns 0 if `lo > hi`.
+    /// # Complexity
+    /// O(log n + k), where k is the number of distinct elements in the range
+    pub fn distinct_between(&self, lo: &T, hi: &T) -> usize {
+        if lo > hi {
+            return 0;
+        }
+        self.count_range_of(lo..=hi).0
+    }
+
+    /// Returns the number of elements, counted with multiplicity, within the inclusive range `[lo, hi]`. Returns 0 if `lo > hi`.
+    /// # Complexity
+    /// O(log n + k), where k is the number of distinct elements in the range
+    pub fn total_between(&self, lo: &T, hi: &T) -> usize {
+        if lo > hi {
+            return 0;
+        }
+        self.count_range_of(lo..=hi).1
+    }
+
+    /// Returns the coordinate compression of the `TreeMultiSet`: a map from each distinct element to its 0-based index among the distinct elements in ascending order.
+    /// # Complexity
+    /// O(n)
+    pub fn compress(&self) -> BTreeMap<T, usize> {
+        self.mp.keys().enumerate().map(|(i, k)| (k.clone(), i)).collect()
+    }
+
+    /// Returns the run-length encoding of the `TreeMultiSet`: one `(element, run_length)` pair per distinct element, in ascending order.
+    /// # Complexity
+    /// O(n)
+    pub fn to_rle(&self) -> Vec<(T, usize)> {
+        self.mp.iter().map(|(k, &v)| (k.clone(), v)).collect()
+    }
+
+    /// Reconstructs a `TreeMultiSet` from a run-length encoding, as produced by [`TreeMultiSet::to_rle`].
+    ///
+    /// The runs are validated strictly: every run length must be positive, and the elements must be strictly increasing,
+    /// so a run must not repeat or precede the element of the run before it.
+    /// # Complexity
+    /// O(n)
+    pub fn from_rle(runs: Vec<(T, usize)>) -> Result<Self, BuildError> {
+        for (index, (k, c)) in runs.iter().enumerate() {
+            if *c == 0 {
+                return Err(BuildError::ZeroCount { index });
+            }
+            match index.checked_sub(1).map(|prev| runs[prev].0.cmp(k)) {
+                Some(std::cmp::Ordering::Equal) => return Err(BuildError::DuplicateKey { index }),
+                Some(std::cmp::Ordering::Greater) => return Err(BuildError::Unsorted { index }),
+                _ => {}
+            }
+        }
+
+        let count = runs.iter().map(|&(_, c)| c).sum();
+        let res = Self { mp: runs.into_iter().collect(), count, version: 0 };
+        res.check_invariants();
+        Ok(res)
+    }
+
+    /// Returns the distinct elements in ascending order together with a parallel array of their counts stored as `u32`.
+    /// # Panics
+    /// Panics if the count of any element exceeds `u32::MAX`.
+    /// # Complexity
+    /// O(n)
+    pub fn to_compressed(&self) -> (Vec<T>, Vec<u32>) {
+        let mut keys = Vec::with_capacity(self.mp.len());
+        let mut counts = Vec::with_capacity(self.mp.len());
+        for (k, &v) in self.mp.iter() {
+            keys.push(k.clone());
+            counts.push(u32::try_from(v).expect("count exceeds u32::MAX"));
+        }
+        (keys, counts)
+    }
+
+    /// Folds every distinct element and its count into an accumulator, in ascending order.
+    /// # Complexity
+    /// O(n)
+    pub fn fold_counts<B, F: FnMut(B, &T, usize) -> B>(&self, init: B, mut f: F) -> B {
+        self.mp.iter().fold(init, |acc, (k, &v)| f(acc, k, v))
+    }
+
+    /// Splits the `TreeMultiSet` into two new sets, the first containing the elements for which `pred` returns `true`
+    /// and the second containing the rest. Counts are preserved.
+    /// # Complexity
+    /// O(n)
+    pub fn partition<F: FnMut(&T) -> bool>(&self, mut pred: F) -> (Self, Self) {
+        let mut yes = Self::new();
+        let mut no = Self::new();
+        for (k, &v) in self.mp.iter() {
+            let target = if pred(k) { &mut yes } else { &mut no };
+            target.mp.insert(k.clone(), v);
+            target.count += v;
+        }
+        yes.check_invariants();
+        no.check_invariants();
+        (yes, no)
+    }
+
+    /// Splits the elements into `boundaries.len() + 1` new sets by value, preserving counts.
+    /// Bucket 0 holds the elements less than `boundaries[0]`, bucket `i` those in `[boundaries[i - 1], boundaries[i])`,
+    /// and the last bucket those greater than or equal to the last boundary. The lengths of the buckets sum to `len()`.
+    ///
+    /// `boundaries` must be sorted in non-decreasing order; this is checked with a debug assertion.
+    /// # Complexity
+    /// O(n + m), where m is the number of boundaries
+    pub fn split_into_buckets(&self, boundaries: &[T]) -> Vec<Self> {
+        debug_assert!(boundaries.windows(2).all(|w| w[0] <= w[1]), "boundaries must be sorted");
+
+        let mut buckets: Vec<Self> = (0..=boundaries.len()).map(|_| Self::new()).collect();
+        let mut idx = 0;
+        for (k, &v) in self.mp.iter() {
+            while idx < boundaries.len() && *k >= boundaries[idx] {
+                idx += 1;
+            }
+            buckets[idx].mp.insert(k.clone(), v);
+            buckets[idx].count += v;
+        }
+        for bucket in &buckets {
+            bucket.check_invariants();
+        }
+        buckets
+    }
+
+    /// Returns a new `TreeMultiSet` in which every element `k` is replaced by `(k + shift) mod modulus`,
+    /// summing the counts of elements that collide. Results are normalized into `0..modulus`, also for negative keys and shifts.
+    /// No intermediate value leaves the range of `T`, so any positive `modulus` is supported.
+    /// # Panics
+    /// Panics if `modulus` is not positive.
+    /// # Complexity
+    /// O(n log n)
+    pub fn rotate_keys(&self, modulus: T, shift: T) -> Self
+    where T: Copy + Default + std::ops::Add<Output = T> + std::ops::Sub<Output = T> + std::ops::Rem<Output = T> {
+        assert!(modulus > T::default(), "modulus must be positive");
+
+        let normalize = |x: T| {
+            let r = x % modulus;
+            if r < T::default() { r + modulus } else { r }
+        };
+        let shift = normalize(shift);
+        let wrap_at = modulus - shift;
+        let mut set = Self::new();
+        for (&k, &v) in self.mp.iter() {
+            let r = normalize(k);
+            let r = if r >= wrap_at { r - wrap_at } else { r + shift };
+            *set.mp.entry(r).or_insert(0) += v;
+        }
+        set.count = self.count;
+        set.check_invariants();
+        set
+    }
+
+    /// Returns the sum of the `k` largest elements, counted with multiplicity, or the sum of all elements if `k >= len()`.
+    /// Returns `T::default()` if `k` is zero or the `TreeMultiSet` is empty.
+    ///
+    /// Overflow in the sum behaves as the arithmetic of `T` does.
+    /// # Panics
+    /// Panics if a count that is summed cannot be converted into `T`.
+    /// # Complexity
+    /// O(d), where d is the number of distinct elements among the `k` largest
+    pub fn sum_of_top_k(&self, k: usize) -> T
+    where T: Copy + Default + std::ops::Add<Output = T> + std::ops::Mul<Output = T> + TryFrom<usize> {
+        let mut remaining = k;
+        let top = self.mp.iter().rev().map_while(|(x, &v)| {
+            if remaining == 0 {
+                return None;
+            }
+            let take = v.min(remaining);
+            remaining -= take;
+            Some((x, take))
+        });
+        Self::weighted_sum(top)
+    }
+
+    /// Returns the sum of all elements within a specified range, counted with multiplicity.
+    /// Returns `T::default()` if the range contains no elements.
+    ///
+    /// Overflow in the sum behaves as the arithmetic of `T` does.
+    /// # Panics
+    /// Panics if the count of an element in the range cannot be converted into `T`.
+    /// # Complexity
+    /// O(log n + k), where k is the number of distinct elements in the range
+    pub fn sum_of_range<R>(&self, rng: R) -> T
+    where R: std::ops::RangeBounds<T>,
+          T: Copy + Default + std::ops::Add<Output = T> + std::ops::Mul<Output = T> + TryFrom<usize> {
+        Self::weighted_sum(self.mp.range(rng).map(|(x, &v)| (x, v)))
+    }
+
+    /// Sums `element * count` over the given pairs, starting from `T::default()`.
+    fn weighted_sum<'a, I>(entries: I) -> T
+    where T: 'a + Copy + Default + std::ops::Add<Output = T> + std::ops::Mul<Output = T> + TryFrom<usize>,
+          I: Iterator<Item = (&'a T, usize)> {
+        entries.fold(T::default(), |acc, (&x, v)| {
+            let v = T::try_from(v).unwrap_or_else(|_| panic!("count cannot be represented in T"));
+            acc + x * v
+        })
+    }
+
+    /// Returns the number of elements, counted with multiplicity, within the closed interval `[center - radius, center + radius]`.
+    ///
+    /// The bounds `center - radius` and `center + radius` are never computed, so the query does not underflow or overflow
+    /// at the extremes of `T`; only differences between `center` and stored elements are evaluated,
+    /// and a difference that does not fit in `T` is treated as lying outside the radius.
+    /// # Complexity
+    /// O(log n + k), where k is the number of distinct elements within the interval
+    pub fn count_within(&self, center: T, radius: T) -> usize
+    where T: Copy + CheckedSub {
+        let below = self.mp.range(..center).rev()
+            .take_while(|(&x, _)| matches!(center.checked_sub(x), Some(d) if d <= radius))
+            .map(|(_, &v)| v)
+            .sum::<usize>();
+        let above = self.mp.range(center..)
+            .take_while(|(&x, _)| matches!(x.checked_sub(center), Some(d) if d <= radius))
+            .map(|(_, &v)| v)
+            .sum::<usize>();
+        below + above
+    }
+
+    /// Decrements the count of each element by its count in `other`, saturating at zero and removing elements whose count reaches zero.
+    /// Elements present only in `other` are ignored.
+    /// # Complexity
+    /// O(m log n), where m is the number of distinct elements in `other`
+    pub fn subtract(&mut self, other: &Self) {
+        let before = self.count;
+        for (k, &v) in other.mp.iter() {
+            let Some(c) = self.mp.get_mut(k) else { continue; };
+            let dec = v.min(*c);
+            *c -= dec;
+            self.count -= dec;
+            if *c == 0 {
+                self.mp.remove(k);
+            }
+        }
+        self.touch_if(self.count != before);
+    }
+
+    /// Reduces the count of each element to the minimum of its counts in `self` and `other`,
+    /// removing elements that are not present in `other`.
+    /// # Complexity
+    /// O(n + m)
+    pub fn intersect_with(&mut self, other: &Self) {
+        let mut theirs = other.mp.iter().peekable();
+        let mut removed = 0;
+        self.mp.retain(|k, v| {
+            while theirs.next_if(|(ok, _)| *ok < k).is_some() {}
+            let shared = match theirs.peek() {
+                Some(&(ok, &ov)) if ok == k => ov,
+                _ => 0,
+            };
+            let keep = (*v).min(shared);
+            removed += *v - keep;
+            *v = keep;
+            keep > 0
+        });
+        self.count -= removed;
+        self.touch_if(removed > 0);
+    }
+
+    /// Retains only the elements present in `allow`, removing all others from the `TreeMultiSet`.
+    /// # Complexity
+    /// O(n + m), where m is the size of `allow`
+    pub fn retain_in(&mut self, allow: &BTreeSet<T>) {
+        self.retain_by_membership(allow, true);
+    }
+
+    /// Removes all occurrences of every element present in `block`, keeping all others.
+    /// # Complexity
+    /// O(n + m), where m is the size of `block`
+    pub fn remove_in(&mut self, block: &BTreeSet<T>) {
+        self.retain_by_membership(block, false);
+    }
+
+    /// Keeps the elements whose membership in `keys` equals `keep_members`, walking both in ascending order.
+    fn retain_by_membership(&mut self, keys: &BTreeSet<T>, keep_members: bool) {
+        let mut theirs = keys.iter().peekable();
+        let mut removed = 0;
+        self.mp.retain(|k, v| {
+            while theirs.next_if(|ok| *ok < k).is_some() {}
+            let member = theirs.peek() == Some(&k);
+            if member != keep_members {
+                removed += *v;
+            }
+            member == keep_members
+        });
+        self.count -= removed;
+        self.touch_if(removed > 0);
+    }
+
+    /// Consumes the `TreeMultiSet` and returns its elements in ascending order as a vector.
+    /// Each distinct element is cloned one time fewer than its count, as its last copy is moved into the vector.
+    /// # Complexity
+    /// O(len)
+    pub fn into_flattened(self) -> Vec<T> {
+        let mut res = Vec::with_capacity(self.count);
+        for (k, v) in self.mp {
+            for _ in 1..v {
+                res.push(k.clone());
+            }
+            res.push(k);
+        }
+        res
+    }
+
+    /// Removes all elements outside a specified range and reduces the count of every element within it to one.
+    /// # Complexity
+    /// O(n)
+    pub fn keep_one_each_in_range<R>(&mut self, rng: R)
+    where R: std::ops::RangeBounds<T> {
+        let before = self.count;
+        self.mp.retain(|k, v| {
+            *v = 1;
+            rng.contains(k)
+        });
+        self.count = self.mp.len();
+        self.touch_if(self.count != before);
+    }
+
+    /// Removes all elements within a specified range and then inserts every element yielded by `replacement`.
+    /// The replacement elements are inserted as usual, so they may land inside or outside the removed range.
+    /// # Complexity
+    /// O(n + m log n), where m is the number of replacement elements
+    pub fn replace_range<R, I>(&mut self, rng: R, replacement: I)
+    where R: std::ops::RangeBounds<T>, I: IntoIterator<Item = T> {
+        let mut removed = 0;
+        self.mp.retain(|k, v| {
+            let inside = rng.contains(k);
+            if inside {
+                removed += *v;
+            }
+            !inside
+        });
+        self.count -= removed;
+
+        let mut inserted = false;
+        for k in replacement {
+            *self.mp.entry(k).or_insert(0) += 1;
+            self.count += 1;
+            inserted = true;
+        }
+        self.touch_if(removed > 0 || inserted);
+    }
+
+    /// Returns `true` if every distinct element has the same count, `false` otherwise.
+    /// An empty `TreeMultiSet` is uniform.
+    /// # Complexity
+    /// O(n)
+    pub fn is_uniform(&self) -> bool {
+        let mut counts = self.mp.values();
+        let Some(first) = counts.next() else { return true; };
+        counts.all(|v| v == first)
+    }
+
+    /// Returns the Shannon entropy, in bits, of the distribution of elements, using `count / len` as the probability of each element.
+    /// Returns `0.0` if the `TreeMultiSet` is empty or contains only one distinct element.
+    /// # Complexity
+    /// O(n)
+    pub fn entropy(&self) -> f64 {
+        if self.mp.len() <= 1 {
+            return 0.0;
+        }
+
+        let len = self.count as f64;
+        self.mp.values()
+            .map(|&v| {
+                let p = v as f64 / len;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// Returns the Gini-Simpson diversity index `1 - sum((count / len)^2)` of the distribution of elements.
+    /// Returns `0.0` if the `TreeMultiSet` is empty.
+    /// # Complexity
+    /// O(n)
+    pub fn simpson_index(&self) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let len = self.count as f64;
+        1.0 - self.mp.values()
+            .map(|&v| {
+                let p = v as f64 / len;
+                p * p
+            })
+            .sum::<f64>()
+    }
+
+    /// Returns the number of unordered pairs of equal elements, i.e. the sum of `count * (count - 1) / 2` over the distinct elements.
+    /// # Panics
+    /// Panics if the result overflows `usize`.
+    /// # Complexity
+    /// O(n)
+    pub fn equal_pair_count(&self) -> usize {
+        self.mp.values()
+            .try_fold(0usize, |acc, &v| acc.checked_add(Self::pairs_among(v)?))
+            .expect("pair count overflowed usize")
+    }
+
+    /// Returns the number of unordered pairs of elements with different values, i.e. `len() * (len() - 1) / 2 - equal_pair_count()`.
+    /// # Panics
+    /// Panics if `len() * (len() - 1) / 2` overflows `usize`, which requires more than about `2^32.5` elements on 64-bit targets.
+    /// # Complexity
+    /// O(n)
+    pub fn distinct_value_pair_count(&self) -> usize {
+        Self::pairs_among(self.count).expect("pair count overflowed usize") - self.equal_pair_count()
+    }
+
+    /// Returns `n * (n - 1) / 2`, or `None` if it overflows `usize`. The halving happens first, so only the result itself can overflow.
+    // `usize::is_multiple_of` would require Rust 1.87.
+    #[allow(clippy::manual_is_multiple_of)]
+    fn pairs_among(n: usize) -> Option<usize> {
+        if n % 2 == 0 {
+            (n / 2).checked_mul(n.saturating_sub(1))
+        } else {
+            n.checked_mul((n - 1) / 2)
+        }
+    }
+
+    /// Returns the number of elements, counted with multiplicity, for which `pred` returns `true`.
+    /// The predicate is called once per distinct element.
+    /// # Complexity
+    /// O(n)
+    pub fn count_if<F: FnMut(&T) -> bool>(&self, mut pred: F) -> usize {
+        self.mp.iter().filter(|(k, _)| pred(k)).map(|(_, &v)| v).sum()
+    }
+
+    /// Returns the number of elements within a specified range, counted with multiplicity, for which `pred` returns `true`.
+    /// The predicate is called once per distinct element in the range.
+    /// # Complexity
+    /// O(log n + k), where k is the number of distinct elements in the range
+    pub fn count_where_in_range<R, F>(&self, rng: R, mut pred: F) -> usize
+    where R: std::ops::RangeBounds<T>, F: FnMut(&T) -> bool {
+        self.mp.range(rng).filter(|(k, _)| pred(k)).map(|(_, &v)| v).sum()
+    }
+
+    /// Returns `true` if `pred` returns `true` for at least one distinct element, stopping at the first match.
+    /// # Complexity
+    /// O(n)
+    pub fn any_key<F: FnMut(&T) -> bool>(&self, pred: F) -> bool {
+        self.mp.keys().any(pred)
+    }
+
+    /// Returns `true` if `pred` returns `true` for every distinct element, stopping at the first mismatch.
+    /// An empty `TreeMultiSet` returns `true`.
+    /// # Complexity
+    /// O(n)
+    pub fn all_keys<F: FnMut(&T) -> bool>(&self, pred: F) -> bool {
+        self.mp.keys().all(pred)
+    }
+
+    /// Appends the elements of the `TreeMultiSet` in ascending order to the end of `out`, reserving space for them first.
+    /// # Complexity
+    /// O(len)
+    pub fn extend_vec(&self, out: &mut Vec<T>) {
+        out.reserve(self.count);
+        out.extend(self.iter().cloned());
+    }
+
+    /// Returns the number of distinct elements whose count is strictly greater than `threshold`.
+    /// # Complexity
+    /// O(n)
+    pub fn distinct_above(&self, threshold: usize) -> usize {
+        self.mp.values().filter(|&&v| v > threshold).count()
+    }
+
+    /// Returns the number of distinct elements whose count is strictly less than `threshold`.
+    /// # Complexity
+    /// O(n)
+    pub fn distinct_below(&self, threshold: usize) -> usize {
+        self.mp.values().filter(|&&v| v < threshold).count()
+    }
+
+    /// Raises the count of each element to the maximum of its counts in `self` and `other`,
+    /// inserting elements that are only present in `other`.
+    /// # Complexity
+    /// O(m log n), where m is the number of distinct elements in `other`
+    pub fn merge_max(&mut self, other: &Self) {
+        let before = self.count;
+        for (k, &v) in other.mp.iter() {
+            if let Some(c) = self.mp.get_mut(k) {
+                if *c < v {
+                    self.count += v - *c;
+                    *c = v;
+                }
+            } else {
+                self.mp.insert(k.clone(), v);
+                self.count += v;
+            }
+        }
+        self.touch_if(self.count != before);
+    }
+
+    /// Lowers the count of each element to the minimum of its counts in `self` and `other`,
+    /// removing elements that are not present in `other`. Equivalent to [`TreeMultiSet::intersect_with`].
+    /// # Complexity
+    /// O(n + m)
+    pub fn merge_min(&mut self, other: &Self) {
+        self.intersect_with(other);
+    }
+
+    /// Winsorizes the `TreeMultiSet`: elements below the `lower_q` quantile are replaced by the value at that quantile,
+    /// and elements above the `upper_q` quantile by the value at that quantile, so `len()` is preserved.
+    ///
+    /// Quantiles are clamped to `[0, 1]`, and the value at quantile `q` is the element at position `round(q * (len - 1))` in ascending order.
+    /// # Panics
+    /// Panics if `lower_q > upper_q` or either quantile is NaN.
+    /// # Complexity
+    /// O(n)
+    pub fn winsorize(&mut self, lower_q: f64, upper_q: f64) {
+        assert!(lower_q <= upper_q, "lower_q must not exceed upper_q");
+        if self.is_empty() {
+            return;
+        }
+
+        let last = (self.count - 1) as f64;
+        let position = |q: f64| (q.clamp(0.0, 1.0) * last).round() as usize;
+        let lo = self.nth_entry(position(lower_q)).expect("position is less than len").0.clone();
+        let hi = self.nth_entry(position(upper_q)).expect("position is less than len").0.clone();
+        self.clamp(lo, hi);
+    }
+
+    /// Moves the counts of all elements below `min` onto `min` and of all elements above `max` onto `max`, so `len()` is preserved.
+    /// Elements within `[min, max]` are left untouched.
+    /// # Panics
+    /// Panics if `min > max`.
+    /// # Complexity
+    /// O(n)
+    pub fn clamp(&mut self, min: T, max: T) {
+        assert!(min <= max, "min must not exceed max");
+
+        let mut above = self.mp.split_off(&max);
+        let at_max = above.remove(&max).unwrap_or(0);
+        let mid = self.mp.split_off(&min);
+        let below = std::mem::replace(&mut self.mp, mid);
+        let below_count = below.values().sum::<usize>();
+        let above_count = above.values().sum::<usize>();
+        if below_count > 0 {
+            *self.mp.entry(min).or_insert(0) += below_count;
+        }
+        if at_max + above_count > 0 {
+            *self.mp.entry(max).or_insert(0) += at_max + above_count;
+        }
+        self.touch_if(below_count > 0 || above_count > 0);
+    }
+
+    /// Folds every distinct element within a specified range and its count into an accumulator, in ascending order.
+    /// # Complexity
+    /// O(log n + k), where k is the number of distinct elements in the range
+    pub fn fold_range<R, B, F>(&self, rng: R, init: B, mut f: F) -> B
+    where R: std::ops::RangeBounds<T>, F: FnMut(B, &T, usize) -> B {
+        self.mp.range(rng).fold(init, |acc, (k, &v)| f(acc, k, v))
+    }
+
+    /// Collects the distinct elements and their counts, as `(element, count)` pairs in ascending order, into any collection.
+    /// # Complexity
+    /// O(n)
+    pub fn to_count_map<M: FromIterator<(T, usize)>>(&self) -> M {
+        self.mp.iter().map(|(k, &v)| (k.clone(), v)).collect()
+    }
+
+    /// Returns the number of distinct elements for which `pred` returns `true`, ignoring counts.
+    /// # Complexity
+    /// O(n)
+    pub fn distinct_count_where<F: FnMut(&T) -> bool>(&self, mut pred: F) -> usize {
+        self.mp.keys().filter(|k| pred(k)).count()
+    }
+
+    /// Consumes the `TreeMultiSet` and returns a `BTreeSet` of its distinct elements, discarding counts.
+    /// The elements are moved rather than cloned.
+    /// # Complexity
+    /// O(n)
+    pub fn into_btree_set(self) -> BTreeSet<T> {
+        self.mp.into_keys().collect()
+    }
+
+    /// Increments the count of every element already present within a specified range by `delta`.
+    /// Values in the range that are not present are not inserted.
+    /// # Complexity
+    /// O(log n + k), where k is the number of distinct elements in the range
+    pub fn add_to_range<R>(&mut self, rng: R, delta: usize)
+    where R: std::ops::RangeBounds<T> {
+        if delta == 0 {
+            return;
+        }
+        let before = self.count;
+        for (_, v) in self.mp.range_mut(rng) {
+            *v += delta;
+            self.count += delta;
+        }
+        self.touch_if(self.count != before);
+    }
+
+    /// Returns `true` if both sets contain the same elements with the same counts, `false` otherwise.
+    /// The totals and numbers of distinct elements are compared first, and the maps are then walked in lockstep,
+    /// stopping at the first difference without allocating.
+    /// # Complexity
+    /// O(min(n, m))
+    pub fn count_eq(&self, other: &Self) -> bool {
+        self.count == other.count
+            && self.mp.len() == other.mp.len()
+            && self.mp.iter().eq(other.mp.iter())
+    }
+
+    /// Scales the count of every element by `numerator / denominator`, rounding down,
+    /// and removes elements whose count becomes zero. For example, `decay(1, 2)` halves every count.
+    /// # Panics
+    /// Panics if `denominator` is zero, or if a scaled count does not fit in `usize`.
+    /// # Complexity
+    /// O(n)
+    pub fn decay(&mut self, numerator: usize, denominator: usize) {
+        assert!(denominator != 0, "denominator must be non-zero");
+
+        let mut count = 0;
+        self.mp.retain(|_, v| {
+            let scaled = *v as u128 * numerator as u128 / denominator as u128;
+            *v = usize::try_from(scaled).expect("scaled count overflowed usize");
+            count += *v;
+            *v > 0
+        });
+        let changed = count != self.count;
+        self.count = count;
+        self.touch_if(changed);
+    }
+
+    /// Returns a `TreeMultiSet` of the gaps between consecutive distinct elements, i.e. `next - prev` for each adjacent pair.
+    /// Only consecutive pairs are considered, which keeps this linear instead of quadratic in the number of distinct elements.
+    /// # Complexity
+    /// O(n log n)
+    pub fn pairwise_gaps(&self) -> TreeMultiSet<<T as std::ops::Sub>::Output>
+    where T: std::ops::Sub, <T as std::ops::Sub>::Output: std::cmp::Ord + Clone {
+        let mut gaps = TreeMultiSet::new();
+        for (prev, next) in self.mp.keys().zip(self.mp.keys().skip(1)) {
+            gaps.add_run(next.clone() - prev.clone(), 1);
+        }
+        gaps.check_invariants();
+        gaps
+    }
+
+    /// Returns the number of runs of repeated elements in the ascending sequence, i.e. the number of distinct elements
+    /// that occur at least twice. Runs of length one are not counted, since every distinct element forms a run.
+    /// # Complexity
+    /// O(n)
+    pub fn run_count(&self) -> usize {
+        self.distinct_above(1)
+    }
+
+    /// Returns a reference to the element with the highest count within a specified range, or `None` if the range contains no elements.
+    /// Ties are broken by the smallest element.
+    /// # Complexity
+    /// O(log n + k), where k is the number of distinct elements in the range
+    pub fn mode_in_range<R>(&self, rng: R) -> Option<&T>
+    where R: std::ops::RangeBounds<T> {
+        let mut best: Option<(&T, usize)> = None;
+        for (k, &v) in self.mp.range(rng) {
+            if !matches!(best, Some((_, bv)) if v <= bv) {
+                best = Some((k, v));
+            }
+        }
+        best.map(|(k, _)| k)
+    }
+
+    /// Returns an iterator over the elements of the `TreeMultiSet`.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.mp.iter().flat_map(|(k , &v)| (0..v).map(move |_| k))
+    }
+
+    /// Returns an iterator over the elements of the `TreeMultiSet` that are greater than or equal to `start`.
+    /// Equivalent to `range(start..)`.
+    pub fn iter_from<'a, Q>(&'a self, start: &Q) -> impl DoubleEndedIterator<Item = &'a T>
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        self.mp.range((Bound::Included(start), Bound::Unbounded)).flat_map(|(k , &v)| (0..v).map(move |_| k))
+    }
+
+    /// Returns an iterator over the elements of the `TreeMultiSet` that are less than `end`.
+    /// Equivalent to `range(..end)`.
+    pub fn iter_until<'a, Q>(&'a self, end: &Q) -> impl DoubleEndedIterator<Item = &'a T>
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        self.mp.range((Bound::Unbounded, Bound::Excluded(end))).flat_map(|(k , &v)| (0..v).map(move |_| k))
+    }
+
+    /// Returns an iterator over the `k` smallest distinct elements of the `TreeMultiSet` in ascending order.
+    pub fn smallest_distinct(&self, k: usize) -> impl Iterator<Item = &T> {
+        self.mp.keys().take(k)
+    }
+
+    /// Returns an iterator over the `k` largest distinct elements of the `TreeMultiSet` in descending order.
+    pub fn largest_distinct(&self, k: usize) -> impl Iterator<Item = &T> {
+        self.mp.keys().rev().take(k)
+    }
+
+    /// Returns an iterator over the runs of equal elements in ascending order, as `(element, run_length)` pairs.
+    pub fn groups(&self) -> impl DoubleEndedIterator<Item = (&T, usize)> {
+        self.mp.iter().map(|(k, &v)| (k, v))
+    }
+
+    /// Returns an iterator over the runs of equal elements in ascending order,
+    /// where each run is itself a lazy iterator yielding its element as many times as it occurs.
+    pub fn group_slices(&self) -> impl DoubleEndedIterator<Item = impl Iterator<Item = &T>> {
+        self.mp.iter().map(|(k, &v)| (0..v).map(move |_| k))
+    }
+
+    /// Returns an iterator over `(position, element)` pairs of the `TreeMultiSet` in ascending order.
+    pub fn iter_indexed(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.iter().enumerate()
+    }
+
+    /// Returns an iterator over the elements of the `TreeMultiSet` in ascending order,
+    /// each paired with its 0-based index among the occurrences of that element.
+    pub fn iter_run_indexed(&self) -> impl Iterator<Item = (&T, usize)> {
+        self.mp.iter().flat_map(|(k , &v)| (0..v).map(move |i| (k, i)))
+    }
+
+    /// Returns an iterator over the elements of the `TreeMultiSet` within a specified range.
+    pub fn range<R>(&self, rng: R) -> impl DoubleEndedIterator<Item = &T>
+    where R: std::ops::RangeBounds<T> {
+        self.mp.range(rng).flat_map(|(k , &v)| (0..v).map(move |_| k))
+    }
+}
+
+impl<T> TreeMultiSet<T> {
+    /// Checks the internal consistency of the `TreeMultiSet`: the total count equals the sum of the counts of all elements,
+    /// and no element is stored with a count of zero.
+    ///
+    /// Requires the `debug-invariants` feature, which also runs this check at the end of every mutating method.
+    /// It is intended for testing only.
+    /// # Panics
+    /// Panics if an invariant is violated.
+    /// # Complexity
+    /// O(n)
+    #[cfg(feature = "debug-invariants")]
+    pub fn assert_invariants(&self) {
+        assert!(self.mp.values().all(|&v| v != 0), "an element is stored with a count of zero");
+        assert_eq!(self.count, self.mp.values().sum::<usize>(), "total count does not match the sum of the counts");
+    }
+
+    /// Runs [`TreeMultiSet::assert_invariants`] if the `debug-invariants` feature is enabled, and does nothing otherwise.
+    #[inline(always)]
+    fn check_invariants(&self) {
+        #[cfg(feature = "debug-invariants")]
+        self.assert_invariants();
+    }
+
+    /// Records a mutation: bumps the version and checks the invariants. Called at the end of every mutating method that changed the set.
+    #[inline(always)]
+    fn touch(&mut self) {
+        self.version = self.version.wrapping_add(1);
+        self.check_invariants();
+    }
+
+    /// Records a mutation if `changed` is `true`, for methods that may turn out to have nothing to do.
+    fn touch_if(&mut self, changed: bool) {
+        if changed {
+            self.touch();
+        }
+    }
+}
+
+impl<T: std::cmp::Ord + Clone> Default for TreeMultiSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A mutable reference to the count of an element in a `TreeMultiSet`.
+///
+/// This struct is created by [`TreeMultiSet::count_mut_or_insert`].
+/// The count is written back when it is dropped.
+pub struct CountMut<'a, T: std::cmp::Ord> {
+    set: &'a mut TreeMultiSet<T>,
+    key: Option<T>,
+    old: usize,
+    value: usize,
+}
+
+impl<T: std::cmp::Ord> std::ops::Deref for CountMut<'_, T> {
+    type Target = usize;
+
+    fn deref(&self) -> &usize {
+        &self.value
+    }
+}
+
+impl<T: std::cmp::Ord> std::ops::DerefMut for CountMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut usize {
+        &mut self.value
+    }
+}
+
+impl<T: std::cmp::Ord> Drop for CountMut<'_, T> {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else { return; };
+        if self.value == self.old {
+            return;
+        }
+        self.set.count = self.set.count - self.old + self.value;
+        if self.value == 0 {
+            self.set.mp.remove(&key);
+        } else {
+            self.set.mp.insert(key, self.value);
+        }
+        self.set.touch();
+    }
+}
+
+/// The error returned when an operation would overflow the count of a `TreeMultiSet`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CountOverflow;
+
+impl std::fmt::Display for CountOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "count overflowed usize")
+    }
+}
+
+impl std::error::Error for CountOverflow {}
+
+/// The error returned when a `TreeMultiSet` cannot be built from externally supplied data.
+///
+/// This enum is returned by [`TreeMultiSet::try_from_pairs`] and [`TreeMultiSet::from_rle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// The pair at `index` has a count of zero.
+    ZeroCount { index: usize },
+    /// The element of the pair at `index` already appeared in an earlier pair.
+    DuplicateKey { index: usize },
+    /// The element of the pair at `index` is smaller than the element of the pair before it.
+    Unsorted { index: usize },
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::ZeroCount { index } => write!(f, "pair {} has a count of zero", index),
+            BuildError::DuplicateKey { index } => write!(f, "pair {} repeats an earlier element", index),
+            BuildError::Unsorted { index } => write!(f, "pair {} is out of order", index),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// A snapshot of the state of a `TreeMultiSet`, used to detect later mutations.
+///
+/// This struct is created by [`TreeMultiSet::snapshot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    len: usize,
+    version: u64,
+}
+
+/// Which end of a `BoundedTreeMultiSet` is evicted when it holds too many distinct elements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict all occurrences of the smallest element.
+    Smallest,
+    /// Evict all occurrences of the largest element.
+    Largest,
+}
+
+/// A `TreeMultiSet` that holds at most a fixed number of distinct elements.
+///
+/// When an insertion adds a new distinct element beyond the limit, all occurrences of the smallest or largest element,
+/// as chosen by the `EvictionPolicy`, are evicted. Eviction happens after the insertion,
+/// so the newly inserted element itself is evicted if it lies at the evicted end.
+pub struct BoundedTreeMultiSet<T> {
+    set: TreeMultiSet<T>,
+    max_distinct: usize,
+    policy: EvictionPolicy,
+}
+
+impl<T: std::cmp::Ord + Clone> BoundedTreeMultiSet<T> {
+    /// Constructs a new, empty `BoundedTreeMultiSet` holding at most `max_distinct` distinct elements.
+    /// # Panics
+    /// Panics if `max_distinct` is zero.
+    pub fn new(max_distinct: usize, policy: EvictionPolicy) -> Self {
+        assert!(max_distinct != 0, "max_distinct must be non-zero");
+        Self {
+            set: TreeMultiSet::new(),
+            max_distinct,
+            policy,
+        }
+    }
+
+    /// Returns the maximum number of distinct elements.
+    pub fn max_distinct(&self) -> usize {
+        self.max_distinct
+    }
+
+    /// Returns the eviction policy.
+    pub fn policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    /// Returns a reference to the underlying `TreeMultiSet`.
+    pub fn as_set(&self) -> &TreeMultiSet<T> {
+        &self.set
+    }
+
+    /// Consumes the `BoundedTreeMultiSet` and returns the underlying `TreeMultiSet`.
+    pub fn into_inner(self) -> TreeMultiSet<T> {
+        self.set
+    }
+
+    /// Inserts an element, evicting an element at the configured end if the limit on distinct elements is exceeded.
+    /// Returns the evicted element together with its count, or `None` if nothing was evicted.
+    /// # Complexity
+    /// O(log n)
+    pub fn insert(&mut self, k: T) -> Option<(T, usize)> {
+        *self.set.mp.entry(k).or_insert(0) += 1;
+        self.set.count += 1;
+        let evicted = if self.set.mp.len() > self.max_distinct {
+            match self.policy {
+                EvictionPolicy::Smallest => self.set.mp.pop_first(),
+                EvictionPolicy::Largest => self.set.mp.pop_last(),
+            }
+        } else {
+            None
+        };
+        if let Some((_, v)) = &evicted {
+            self.set.count -= v;
+        }
+        self.set.touch();
+        evicted
+    }
+
+    /// Removes one occurrence of a specified element and returns it, or `None` if the element is not present.
+    /// # Complexity
+    /// O(log n)
+    pub fn remove_one<Q>(&mut self, k: &Q) -> Option<T>
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        self.set.remove_one(k)
+    }
+
+    /// Removes all occurrences of a specified element and returns it, or `None` if the element is not present.
+    /// # Complexity
+    /// O(log n)
+    pub fn remove_all<Q>(&mut self, k: &Q) -> Option<T>
+    where T: Borrow<Q>, Q: std::cmp::Ord + ?Sized {
+        self.set.remove_all(k)
+    }
+
+    /// Removes all elements, leaving the `BoundedTreeMultiSet` empty.
+    pub fn clear(&mut self) {
+        self.set.clear();
+    }
+}
+
+/// Subtraction that reports overflow by returning `None`, used by the distance-based queries of `TreeMultiSet`.
+///
+/// This trait is implemented for all primitive integer types.
+pub trait CheckedSub: Sized {
+    /// Returns `self - rhs`, or `None` if the result does not fit in `Self`.
+    fn checked_sub(self, rhs: Self) -> Option<Self>;
+}
+
+macro_rules! impl_checked_sub {
+    ($($t:ty),*) => {
+        $(
+            impl CheckedSub for $t {
+                fn checked_sub(self, rhs: Self) -> Option<Self> {
+                    <$t>::checked_sub(self, rhs)
+                }
+            }
+        )*
+    };
+}
+
+impl_checked_sub!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
     
     #[test]
-    fn test_count() {
-        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
-        set.insert(1);
-        set.insert(2);
-        set.insert(3);
-        set.insert(4);
-        set.insert(4);
-        set.insert(5);
-        set.insert(3);
+    fn test_count() {
+        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(2);
+        set.insert(3);
+        set.insert(4);
+        set.insert(4);
+        set.insert(5);
+        set.insert(3);
+
+        assert_eq!(set.count(&1), 1);
+        assert_eq!(set.count(&2), 1);
+        assert_eq!(set.count(&3), 2);
+        assert_eq!(set.count(&4), 2);
+        assert_eq!(set.count(&5), 1);
+        assert_eq!(set.count(&6), 0);
+    }
+
+    #[test]
+    fn test_empty_test() {
+        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
+        assert!(set.is_empty());
+        assert_eq!(set.len(), 0);
+        assert_eq!(set.first(), None);
+        assert_eq!(set.last(), None);
+        assert_eq!(set.pop_first(), None);
+        assert_eq!(set.pop_last(), None);
+        assert_eq!(set.iter().next(), None);
+        assert_eq!(set.range(0..10).next(), None);
+        assert_eq!(set.range(..).next(), None);
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(2);
+        set.insert(3);
+        set.insert(4);
+        set.insert(4);
+        set.insert(5);
+        set.insert(3);
+
+        assert_eq!(set.len(), 7);
+        assert_eq!(set.first(), Some(&1));
+        assert_eq!(set.last(), Some(&5));
+        assert_eq!(set.pop_first(), Some(1));
+        assert_eq!(set.first(), Some(&2));
+        assert_eq!(set.len(), 6);
+        assert_eq!(set.pop_last(), Some(5));
+        assert_eq!(set.last(), Some(&4));
+        assert_eq!(set.len(), 5);
+
+        assert_eq!(set.remove_one(&3), Some(3));
+        assert_eq!(set.len(), 4);
+        assert_eq!(set.remove_one(&3), Some(3));
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.remove_one(&3), None);
+        assert_eq!(set.len(), 3);
+
+        assert_eq!(set.remove_all(&4), Some(4));
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.remove_all(&4), None);
+
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&2]);
+    }
+
+    #[test]
+    fn test_contains() {
+        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(2);
+        set.insert(3);
+        set.insert(4);
+        set.insert(4);
+        set.insert(5);
+
+        assert!(set.contains(&1));
+        assert!(set.contains(&2));
+        assert!(set.contains(&3));
+        assert!(set.contains(&4));
+        assert!(set.contains(&5));
+
+        assert!(!set.contains(&6));
+        assert!(!set.contains(&7));
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(2);
+        set.insert(3);
+        set.insert(3);
+        set.insert(4);
+        set.insert(5);
+        set.insert(5);
+
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &2, &3, &3, &4, &5, &5]);
+    }
+
+    #[test]
+    fn test_rev_iter() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(2);
+        set.insert(3);
+        set.insert(3);
+        set.insert(4);
+        set.insert(5);
+        set.insert(5);
+    }
+
+    #[test]
+    fn test_range() {
+        let mut set = TreeMultiSet::new();
+        for i in 1..=4 {
+            for _ in 0..i {
+                set.insert(i);
+            }
+        }
+
+        assert_eq!(set.range(1..=4).collect::<Vec<_>>(), vec![&1, &2, &2, &3, &3, &3, &4, &4, &4, &4]);
+        assert_eq!(set.range(1..3).collect::<Vec<_>>(), vec![&1, &2, &2]);
+        assert_eq!(set.range(..3).collect::<Vec<_>>(), vec![&1, &2, &2]);
+        assert_eq!(set.range(..=3).collect::<Vec<_>>(), vec![&1, &2, &2, &3, &3, &3]);
+        assert_eq!(set.range(..=3).rev().collect::<Vec<_>>(), vec![&3, &3, &3, &2, &2, &1]);
+        assert_eq!(set.range(2..).collect::<Vec<_>>(), vec![&2, &2, &3, &3, &3, &4, &4, &4, &4]);
+        assert_eq!(set.range(2..=4).rev().collect::<Vec<_>>(), vec![&4, &4, &4, &4, &3, &3, &3, &2, &2]);
+    }
+
+    #[test]
+    fn test_contains_all_any() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(2);
+        set.insert(2);
+        set.insert(4);
+
+        assert!(set.contains_all(vec![1, 2, 4]));
+        assert!(!set.contains_all(vec![1, 3, 4]));
+        assert!(set.contains_all(Vec::new()));
+        assert!(set.contains_any(vec![3, 4]));
+        assert!(!set.contains_any(vec![3, 5]));
+        assert!(!set.contains_any(Vec::new()));
+
+        let mut visited = 0;
+        assert!(!set.contains_all([1, 3, 4, 2].into_iter().inspect(|_| visited += 1)));
+        assert_eq!(visited, 2);
+
+        let mut visited = 0;
+        assert!(set.contains_any([3, 2, 5, 1].into_iter().inspect(|_| visited += 1)));
+        assert_eq!(visited, 2);
+    }
+
+    #[test]
+    fn test_set_all_counts() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(2);
+        set.insert(2);
+        set.insert(2);
+        set.insert(3);
+
+        set.set_all_counts(2);
+        assert_eq!(set.len(), 6);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &1, &2, &2, &3, &3]);
+
+        set.set_all_counts(0);
+        assert!(set.is_empty());
+        assert_eq!(set.iter().next(), None);
+    }
+
+    #[test]
+    fn test_split_at_rank() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(2);
+        set.insert(2);
+        set.insert(3);
+
+        let right = set.split_at_rank(2);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &2]);
+        assert_eq!(set.len(), 2);
+        assert_eq!(right.iter().collect::<Vec<_>>(), vec![&2, &3]);
+        assert_eq!(right.len(), 2);
+
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(2);
+        set.insert(2);
+        set.insert(3);
+
+        let right = set.split_at_rank(3);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &2, &2]);
+        assert_eq!(right.iter().collect::<Vec<_>>(), vec![&3]);
+
+        let right = set.split_at_rank(10);
+        assert!(right.is_empty());
+        assert_eq!(set.len(), 3);
+
+        let right = set.split_at_rank(0);
+        assert!(set.is_empty());
+        assert_eq!(right.iter().collect::<Vec<_>>(), vec![&1, &2, &2]);
+        assert_eq!(right.len(), 3);
+    }
+
+    #[test]
+    fn test_retain_range() {
+        let mut set = TreeMultiSet::new();
+        for i in 1..=5 {
+            set.insert(i);
+            set.insert(i);
+        }
+
+        set.retain_range(2..=4);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&2, &2, &3, &3, &4, &4]);
+        assert_eq!(set.len(), 6);
+
+        set.retain_range(2..4);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&2, &2, &3, &3]);
+        assert_eq!(set.len(), 4);
+
+        set.retain_range(3..);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&3, &3]);
+        assert_eq!(set.len(), 2);
+
+        set.retain_range(3..3);
+        assert!(set.is_empty());
+        assert_eq!(set.iter().next(), None);
+    }
+
+    #[test]
+    fn test_counts() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(3);
+        set.insert(3);
+
+        let counts = set.counts();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts.get(&1), Some(&1));
+        assert_eq!(counts.get(&3), Some(&2));
+        assert_eq!(counts.range(2..).next(), Some((&3, &2)));
+    }
+
+    #[test]
+    fn test_counts_of() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(2);
+        set.insert(2);
+        set.insert(4);
+
+        assert_eq!(set.counts_of(&[4, 3, 2, 1, 0]), vec![1, 0, 2, 1, 0]);
+        assert_eq!(set.counts_of(&[]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_top_k_frequent() {
+        let mut set = TreeMultiSet::new();
+        for (k, n) in [(1, 2), (2, 5), (3, 1), (4, 5), (5, 3)] {
+            for _ in 0..n {
+                set.insert(k);
+            }
+        }
+
+        assert_eq!(set.top_k_frequent(0), vec![]);
+        assert_eq!(set.top_k_frequent(1), vec![(&2, 5)]);
+        assert_eq!(set.top_k_frequent(3), vec![(&2, 5), (&4, 5), (&5, 3)]);
+        assert_eq!(set.top_k_frequent(10), vec![(&2, 5), (&4, 5), (&5, 3), (&1, 2), (&3, 1)]);
+        assert_eq!(TreeMultiSet::<i32>::new().top_k_frequent(3), vec![]);
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_sample() {
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        for _ in 0..9 {
+            set.insert(2);
+        }
+
+        let all = set.sample(&mut rng, 100);
+        assert_eq!(all, set.iter().cloned().collect::<Vec<_>>());
+        assert!(set.sample(&mut rng, 0).is_empty());
+        assert!(TreeMultiSet::<i32>::new().sample(&mut rng, 3).is_empty());
+
+        let mut ones = 0;
+        for _ in 0..10000 {
+            let drawn = set.sample(&mut rng, 2);
+            assert_eq!(drawn.len(), 2);
+            assert!(drawn.windows(2).all(|w| w[0] <= w[1]));
+            ones += drawn.iter().filter(|&&x| x == 1).count();
+        }
+        // each draw contains `1` with probability 2/10
+        assert!((1700..2300).contains(&ones));
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_choose() {
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        assert_eq!(TreeMultiSet::<i32>::new().choose(&mut rng), None);
+
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        for _ in 0..3 {
+            set.insert(2);
+        }
+        for _ in 0..6 {
+            set.insert(3);
+        }
+
+        let mut hits = [0; 4];
+        for _ in 0..10000 {
+            hits[*set.choose(&mut rng).unwrap()] += 1;
+        }
+        assert_eq!(hits[0], 0);
+        assert!((800..1200).contains(&hits[1]));
+        assert!((2700..3300).contains(&hits[2]));
+        assert!((5600..6400).contains(&hits[3]));
+    }
+
+    #[test]
+    fn test_cap_counts() {
+        let mut set = TreeMultiSet::new();
+        for _ in 0..5 {
+            set.insert('a');
+        }
+        set.insert('b');
+        set.insert('b');
+
+        set.cap_counts(3);
+        assert_eq!(set.count(&'a'), 3);
+        assert_eq!(set.count(&'b'), 2);
+        assert_eq!(set.len(), 5);
+
+        set.cap_counts(1);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&'a', &'b']);
+        assert_eq!(set.len(), 2);
+
+        set.cap_counts(0);
+        assert!(set.is_empty());
+        assert!(!set.contains(&'a'));
+    }
+
+    #[test]
+    fn test_count_mut_or_insert() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(2);
+        set.insert(2);
+
+        *set.count_mut_or_insert(2, 10) += 3;
+        assert_eq!(set.count(&2), 5);
+        assert_eq!(set.len(), 6);
+
+        {
+            let c = set.count_mut_or_insert(3, 4);
+            assert_eq!(*c, 4);
+        }
+        assert_eq!(set.count(&3), 4);
+        assert_eq!(set.len(), 10);
+
+        *set.count_mut_or_insert(1, 7) = 0;
+        assert!(!set.contains(&1));
+        assert_eq!(set.len(), 9);
+
+        let _ = set.count_mut_or_insert(4, 0);
+        assert!(!set.contains(&4));
+        assert_eq!(set.len(), 9);
+        assert_eq!(set.iter().count(), 9);
+    }
+
+    #[test]
+    fn test_smallest_largest_distinct() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 1, 1, 2, 3, 3, 4] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.smallest_distinct(2).collect::<Vec<_>>(), vec![&1, &2]);
+        assert_eq!(set.largest_distinct(2).collect::<Vec<_>>(), vec![&4, &3]);
+        assert_eq!(set.smallest_distinct(10).collect::<Vec<_>>(), vec![&1, &2, &3, &4]);
+        assert_eq!(set.largest_distinct(0).next(), None);
+    }
+
+    #[test]
+    fn test_equal_range() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.equal_range(&1), (0, 1));
+        assert_eq!(set.equal_range(&2), (1, 3));
+        assert_eq!(set.equal_range(&3), (3, 4));
+        assert_eq!(set.equal_range(&0), (0, 0));
+        assert_eq!(set.equal_range(&5), (4, 4));
+    }
+
+    #[test]
+    fn test_keep_every_nth_distinct() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 4, 4, 4, 5] {
+            set.insert(i);
+        }
+
+        set.keep_every_nth_distinct(1);
+        assert_eq!(set.len(), 8);
+
+        set.keep_every_nth_distinct(2);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &3, &5]);
+        assert_eq!(set.len(), 3);
+
+        set.keep_every_nth_distinct(5);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_keep_every_nth_distinct_zero_step() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.keep_every_nth_distinct(0);
+    }
+
+    #[test]
+    fn test_from_sorted_iters() {
+        let set = TreeMultiSet::from_sorted_iters(vec![
+            vec![1, 3, 3, 5],
+            vec![],
+            vec![2, 3, 6],
+            vec![1, 1, 7],
+        ]);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &1, &1, &2, &3, &3, &3, &5, &6, &7]);
+        assert_eq!(set.len(), 10);
+        assert_eq!(set.count(&3), 3);
+        assert_eq!(set.mutation_count(), 0);
+
+        let set = TreeMultiSet::from_sorted_iters(vec![vec![3, 1], vec![1, 2]]);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &1, &2, &3]);
+        assert_eq!(set.len(), 4);
+
+        let set = TreeMultiSet::<i32>::from_sorted_iters(Vec::<Vec<i32>>::new());
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut a = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 5] {
+            a.insert(i);
+        }
+        let mut b = TreeMultiSet::new();
+        for i in [2, 3, 4, 4, 5, 5] {
+            b.insert(i);
+        }
+
+        assert_eq!(a.diff(&b), vec![(1, -1), (2, -1), (4, 2), (5, 1)]);
+        assert_eq!(b.diff(&a), vec![(1, 1), (2, 1), (4, -2), (5, -1)]);
+        assert_eq!(a.diff(&a), vec![]);
+        assert_eq!(TreeMultiSet::new().diff(&a), vec![(1, 1), (2, 2), (3, 1), (5, 1)]);
+
+        let mut big = TreeMultiSet::new();
+        big.insert_run(0, usize::MAX);
+        let expected = i64::try_from(usize::MAX).unwrap_or(i64::MAX);
+        assert_eq!(TreeMultiSet::new().diff(&big), vec![(0, expected)]);
+        assert_eq!(big.diff(&TreeMultiSet::new()), vec![(0, i64::try_from(usize::MAX).map_or(i64::MIN, |d| -d))]);
+    }
+
+    #[test]
+    fn test_apply_diff() {
+        let mut a = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 5] {
+            a.insert(i);
+        }
+        let mut b = TreeMultiSet::new();
+        for i in [2, 3, 4, 4, 5, 5] {
+            b.insert(i);
+        }
+
+        a.apply_diff(a.diff(&b));
+        assert_eq!(a.iter().collect::<Vec<_>>(), b.iter().collect::<Vec<_>>());
+        assert_eq!(a.len(), b.len());
+
+        a.apply_diff(vec![(2, -5), (4, -1), (6, -1), (7, 0), (1, 2)]);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![&1, &1, &3, &4, &5, &5]);
+        assert_eq!(a.len(), 6);
+        assert!(!a.contains(&2));
+        assert!(!a.contains(&7));
+
+        a.apply_diff(vec![(5, i64::MIN)]);
+        assert!(!a.contains(&5));
+        assert_eq!(a.len(), 4);
+    }
+
+    #[test]
+    fn test_estimated_size_bytes() {
+        let mut set: TreeMultiSet<u64> = TreeMultiSet::new();
+        let empty = set.estimated_size_bytes();
+        assert_eq!(empty, std::mem::size_of::<TreeMultiSet<u64>>());
+
+        set.insert(1);
+        let one = set.estimated_size_bytes();
+        assert!(one > empty);
+
+        set.insert(1);
+        assert_eq!(set.estimated_size_bytes(), one);
+
+        for i in 0..1000 {
+            set.insert(i);
+        }
+        assert!(set.estimated_size_bytes() >= 1000 * 16);
+    }
+
+    #[test]
+    fn test_iter_from_until() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 5] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.iter_from(&2).collect::<Vec<_>>(), vec![&2, &2, &3, &5]);
+        assert_eq!(set.iter_from(&4).collect::<Vec<_>>(), vec![&5]);
+        assert_eq!(set.iter_from(&6).next(), None);
+        assert_eq!(set.iter_until(&3).collect::<Vec<_>>(), vec![&1, &2, &2]);
+        assert_eq!(set.iter_until(&3).rev().collect::<Vec<_>>(), vec![&2, &2, &1]);
+        assert_eq!(set.iter_until(&1).next(), None);
+    }
+
+    #[test]
+    fn test_count_range_of() {
+        let mut set = TreeMultiSet::new();
+        for i in 1..=4 {
+            for _ in 0..i {
+                set.insert(i);
+            }
+        }
+
+        assert_eq!(set.count_range_of(..), (4, 10));
+        assert_eq!(set.count_range_of(2..4), (2, 5));
+        assert_eq!(set.count_range_of(2..=4), (3, 9));
+        assert_eq!(set.count_range_of(5..), (0, 0));
+    }
+
+    #[test]
+    fn test_insert_ref() {
+        let mut set = TreeMultiSet::new();
+        let key = String::from("key");
+        set.insert_ref(&key);
+        set.insert_ref(&key);
+        set.insert(String::from("other"));
+        set.insert_ref(&String::from("other"));
+
+        assert_eq!(set.count(&key), 2);
+        assert_eq!(set.count(&String::from("other")), 2);
+        assert_eq!(set.len(), 4);
+    }
+
+    #[test]
+    fn test_borrowed_lookups() {
+        let mut set: TreeMultiSet<String> = TreeMultiSet::new();
+        for s in ["apple", "banana", "banana", "cherry"] {
+            set.insert(s.to_string());
+        }
+
+        assert_eq!(set.count("banana"), 2);
+        assert_eq!(set.count("durian"), 0);
+        assert!(set.contains("apple"));
+        assert!(!set.contains("durian"));
+        assert_eq!(set.equal_range("banana"), (1, 3));
+        assert_eq!(set.iter_from("b").count(), 3);
+        assert_eq!(set.iter_until("c").count(), 3);
+
+        assert_eq!(set.remove_one("banana"), Some("banana".to_string()));
+        assert_eq!(set.count("banana"), 1);
+        assert_eq!(set.remove_one("banana"), Some("banana".to_string()));
+        assert!(!set.contains("banana"));
+        assert_eq!(set.remove_one("banana"), None);
+        assert_eq!(set.remove_all("cherry"), Some("cherry".to_string()));
+        assert_eq!(set.remove_all("cherry"), None);
+        assert_eq!(set.len(), 1);
+
+        let key = "apple".to_string();
+        assert_eq!(set.count(&key), 1);
+    }
+
+    #[test]
+    fn test_try_insert_n() {
+        let mut set = TreeMultiSet::new();
+        assert_eq!(set.try_insert_n(1, 3), Ok(3));
+        assert_eq!(set.try_insert_n(1, 2), Ok(5));
+        assert_eq!(set.try_insert_n(2, 0), Ok(0));
+        assert!(!set.contains(&2));
+        assert_eq!(set.len(), 5);
+
+        assert_eq!(set.try_insert_n(2, usize::MAX), Err(CountOverflow));
+        assert_eq!(set.len(), 5);
+        assert!(!set.contains(&2));
+
+        let mut set = TreeMultiSet::new();
+        assert_eq!(set.try_insert_n(1, usize::MAX - 1), Ok(usize::MAX - 1));
+        assert_eq!(set.try_insert_n(1, 2), Err(CountOverflow));
+        assert_eq!(set.try_insert_n(2, 2), Err(CountOverflow));
+        assert_eq!(set.try_insert_n(2, 1), Ok(1));
+        assert_eq!(set.len(), usize::MAX);
+    }
+
+    #[test]
+    fn test_compressed() {
+        let mut set = TreeMultiSet::new();
+        for i in [3, 1, 2, 2, 3, 3] {
+            set.insert(i);
+        }
+
+        let (keys, counts) = set.to_compressed();
+        assert_eq!(keys, vec![1, 2, 3]);
+        assert_eq!(counts, vec![1, 2, 3]);
+
+        let restored = TreeMultiSet::from_compressed(keys, counts).unwrap();
+        assert_eq!(restored.iter().collect::<Vec<_>>(), set.iter().collect::<Vec<_>>());
+        assert_eq!(restored.len(), 6);
+
+        assert!(TreeMultiSet::from_compressed(vec![1, 2], vec![1]).is_none());
+
+        let restored = TreeMultiSet::from_compressed(vec![2, 1, 2], vec![1, 0, 2]).unwrap();
+        assert_eq!(restored.iter().collect::<Vec<_>>(), vec![&2, &2, &2]);
+        assert_eq!(restored.len(), 3);
+    }
+
+    #[test]
+    fn test_fold_counts() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 3, 3] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.fold_counts(0, |acc, _, c| acc + c), set.len());
+        assert_eq!(set.fold_counts(0, |acc, &k, c| acc + k * c as i32), 14);
+        assert_eq!(set.fold_counts(Vec::new(), |mut acc, &k, c| { acc.push((k, c)); acc }), vec![(1, 1), (2, 2), (3, 3)]);
+        assert_eq!(TreeMultiSet::<i32>::new().fold_counts(7, |acc, _, c| acc + c), 7);
+    }
+
+    #[test]
+    fn test_partition() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 4, 4, 4] {
+            set.insert(i);
+        }
+
+        let (even, odd) = set.partition(|&x| x % 2 == 0);
+        assert_eq!(even.iter().collect::<Vec<_>>(), vec![&2, &2, &4, &4, &4]);
+        assert_eq!(even.len(), 5);
+        assert_eq!(odd.iter().collect::<Vec<_>>(), vec![&1, &3]);
+        assert_eq!(odd.len(), 2);
+        assert_eq!(even.len() + odd.len(), set.len());
+
+        let (all, none) = set.partition(|_| true);
+        assert_eq!(all.len(), set.len());
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn test_rotate_keys() {
+        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
+        for i in [0, 1, 1, 3, 4] {
+            set.insert(i);
+        }
+
+        let rotated = set.rotate_keys(5, 2);
+        assert_eq!(rotated.iter().collect::<Vec<_>>(), vec![&0, &1, &2, &3, &3]);
+        assert_eq!(rotated.len(), set.len());
+
+        let rotated = set.rotate_keys(3, -1);
+        assert_eq!(rotated.iter().collect::<Vec<_>>(), vec![&0, &0, &0, &2, &2]);
+        assert_eq!(rotated.count(&0), 3);
+        assert_eq!(rotated.len(), set.len());
+
+        let mut set: TreeMultiSet<u8> = TreeMultiSet::new();
+        set.insert(250);
+        set.insert(3);
+        let rotated = set.rotate_keys(7, 200);
+        assert_eq!(rotated.iter().collect::<Vec<_>>(), vec![&0, &2]);
+
+        let mut set: TreeMultiSet<u32> = TreeMultiSet::new();
+        set.insert(2_000_000_000);
+        set.insert(2_999_999_999);
+        let rotated = set.rotate_keys(3_000_000_000, 1);
+        assert_eq!(rotated.iter().collect::<Vec<_>>(), vec![&0, &2_000_000_001]);
+        let rotated = set.rotate_keys(u32::MAX, u32::MAX - 1);
+        assert_eq!(rotated.iter().collect::<Vec<_>>(), vec![&1_999_999_999, &2_999_999_998]);
+
+        let mut set: TreeMultiSet<i8> = TreeMultiSet::new();
+        set.insert(i8::MIN);
+        set.insert(i8::MAX);
+        let rotated = set.rotate_keys(100, -90);
+        assert_eq!(rotated.iter().collect::<Vec<_>>(), vec![&37, &82]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rotate_keys_zero_modulus() {
+        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
+        set.insert(1);
+        set.rotate_keys(0, 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rotate_keys_negative_modulus() {
+        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
+        set.insert(1);
+        set.rotate_keys(-3, 1);
+    }
+
+    #[test]
+    fn test_nearest() {
+        let mut set: TreeMultiSet<u32> = TreeMultiSet::new();
+        assert_eq!(set.nearest(&3), None);
+
+        for i in [2, 6, 6, 10] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.nearest(&0), Some(&2));
+        assert_eq!(set.nearest(&2), Some(&2));
+        assert_eq!(set.nearest(&3), Some(&2));
+        assert_eq!(set.nearest(&4), Some(&2));
+        assert_eq!(set.nearest(&5), Some(&6));
+        assert_eq!(set.nearest(&8), Some(&6));
+        assert_eq!(set.nearest(&9), Some(&10));
+        assert_eq!(set.nearest(&100), Some(&10));
+
+        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
+        set.insert(i32::MIN);
+        set.insert(i32::MAX);
+        assert_eq!(set.nearest(&0), Some(&i32::MAX));
+        assert_eq!(set.nearest(&-1), Some(&i32::MIN));
+        assert_eq!(set.nearest(&i32::MIN), Some(&i32::MIN));
+        assert_eq!(set.nearest(&i32::MAX), Some(&i32::MAX));
+    }
+
+    #[test]
+    fn test_count_within() {
+        let mut set: TreeMultiSet<u8> = TreeMultiSet::new();
+        for i in [0, 1, 1, 3, 5, 5, 5, 8, 255] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.count_within(4, 1), 4);
+        assert_eq!(set.count_within(4, 0), 0);
+        assert_eq!(set.count_within(5, 0), 3);
+        assert_eq!(set.count_within(1, 5), 7);
+        assert_eq!(set.count_within(254, 10), 1);
+        assert_eq!(set.count_within(0, 255), 9);
+
+        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
+        for i in [-5, -1, 0, 2, 2, 7] {
+            set.insert(i);
+        }
+        assert_eq!(set.count_within(0, 2), 4);
+        assert_eq!(set.count_within(-3, 2), 2);
+
+        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
+        set.insert(i32::MIN);
+        set.insert(i32::MAX);
+        assert_eq!(set.count_within(0, 5), 0);
+        assert_eq!(set.count_within(-1, 5), 0);
+        assert_eq!(set.count_within(i32::MIN + 3, 5), 1);
+        assert_eq!(set.count_within(i32::MAX, 0), 1);
+        assert_eq!(set.count_within(0, i32::MAX), 1);
+        assert_eq!(set.count_within(-1, i32::MAX), 1);
+    }
+
+    #[test]
+    fn test_min_max() {
+        let mut set = TreeMultiSet::new();
+        assert_eq!(set.min_max(), None);
+
+        set.insert(3);
+        assert_eq!(set.min_max(), Some((&3, &3)));
+
+        set.insert(1);
+        set.insert(5);
+        set.insert(5);
+        assert_eq!(set.min_max(), Some((&1, &5)));
+    }
+
+    #[test]
+    fn test_span() {
+        let mut set = TreeMultiSet::new();
+        assert_eq!(set.span(), None);
+
+        set.insert(5);
+        assert_eq!(set.span(), Some(0));
+
+        set.insert(2);
+        set.insert(9);
+        set.insert(9);
+        assert_eq!(set.span(), Some(7));
+    }
+
+    #[test]
+    fn test_count_of_min_max() {
+        let mut set = TreeMultiSet::new();
+        assert_eq!(set.count_of_min(), 0);
+        assert_eq!(set.count_of_max(), 0);
+
+        for i in [1, 1, 2, 3, 3, 3] {
+            set.insert(i);
+        }
+        assert_eq!(set.count_of_min(), 2);
+        assert_eq!(set.count_of_max(), 3);
+    }
+
+    #[test]
+    fn test_insert_all_from() {
+        let mut a = TreeMultiSet::new();
+        for i in [1, 2, 2] {
+            a.insert(i);
+        }
+        let mut b = TreeMultiSet::new();
+        for i in [2, 3, 3] {
+            b.insert(i);
+        }
+
+        a.insert_all_from(&b);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![&1, &2, &2, &2, &3, &3]);
+        assert_eq!(a.len(), 6);
+        assert_eq!(b.iter().collect::<Vec<_>>(), vec![&2, &3, &3]);
+        assert_eq!(b.len(), 3);
+    }
+
+    #[test]
+    fn test_subtract() {
+        let mut a = TreeMultiSet::new();
+        for c in ['a', 'a', 'a', 'b'] {
+            a.insert(c);
+        }
+        let mut b = TreeMultiSet::new();
+        b.insert('a');
+        b.insert('c');
+
+        a.subtract(&b);
+        assert_eq!(a.count(&'a'), 2);
+        assert_eq!(a.count(&'b'), 1);
+        assert!(!a.contains(&'c'));
+        assert_eq!(a.len(), 3);
+
+        let mut c = TreeMultiSet::new();
+        for _ in 0..5 {
+            c.insert('a');
+        }
+        a.subtract(&c);
+        assert!(!a.contains(&'a'));
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![&'b']);
+        assert_eq!(a.len(), 1);
+    }
+
+    #[test]
+    fn test_intersect_with() {
+        let mut a = TreeMultiSet::new();
+        for i in [1, 2, 2, 2, 3, 5, 5] {
+            a.insert(i);
+        }
+        let mut b = TreeMultiSet::new();
+        for i in [0, 2, 2, 4, 5, 5, 5, 6] {
+            b.insert(i);
+        }
+
+        a.intersect_with(&b);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![&2, &2, &5, &5]);
+        assert_eq!(a.len(), 4);
+
+        a.intersect_with(&TreeMultiSet::new());
+        assert!(a.is_empty());
+        assert_eq!(a.iter().next(), None);
+    }
+
+    #[test]
+    fn test_into_flattened() {
+        let mut set = TreeMultiSet::new();
+        for s in ["b", "a", "b", "c", "b"] {
+            set.insert(s.to_string());
+        }
+
+        assert_eq!(set.into_flattened(), vec!["a", "b", "b", "b", "c"]);
+        assert_eq!(TreeMultiSet::<i32>::new().into_flattened(), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn test_keep_one_each_in_range() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 3, 3, 4, 5, 5] {
+            set.insert(i);
+        }
+
+        set.keep_one_each_in_range(2..5);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&2, &3, &4]);
+        assert_eq!(set.len(), 3);
+
+        set.keep_one_each_in_range(5..);
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut set = TreeMultiSet::new();
+        for i in [3, 1, 3, 2, 3] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.groups().collect::<Vec<_>>(), vec![(&1, 1), (&2, 1), (&3, 3)]);
+        assert_eq!(
+            set.group_slices().map(|run| run.collect::<Vec<_>>()).collect::<Vec<_>>(),
+            vec![vec![&1], vec![&2], vec![&3, &3, &3]]
+        );
+        assert_eq!(TreeMultiSet::<i32>::new().groups().next(), None);
+    }
+
+    #[test]
+    fn test_search() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.search(&1), Ok(0));
+        assert_eq!(set.search(&2), Ok(1));
+        assert_eq!(set.search(&3), Ok(3));
+        assert_eq!(set.search(&0), Err(0));
+        assert_eq!(set.search(&5), Err(4));
+        assert_eq!(TreeMultiSet::<i32>::new().search(&1), Err(0));
+    }
+
+    #[test]
+    fn test_replace_range() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 4, 5] {
+            set.insert(i);
+        }
+
+        set.replace_range(2..=3, vec![7, 2, 2, 2]);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &2, &2, &2, &4, &5, &7]);
+        assert_eq!(set.len(), 7);
+
+        set.replace_range(..3, Vec::new());
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&4, &5, &7]);
+        assert_eq!(set.len(), 3);
+
+        set.replace_range(10.., vec![1]);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &4, &5, &7]);
+        assert_eq!(set.len(), 4);
+    }
+
+    #[test]
+    fn test_is_uniform() {
+        let mut set = TreeMultiSet::new();
+        assert!(set.is_uniform());
+
+        set.insert('a');
+        set.insert('a');
+        set.insert('b');
+        set.insert('b');
+        assert!(set.is_uniform());
+
+        set.insert('b');
+        assert!(!set.is_uniform());
+    }
+
+    #[test]
+    fn test_entropy() {
+        let mut set = TreeMultiSet::new();
+        assert_eq!(set.entropy(), 0.0);
+
+        set.insert(1);
+        set.insert(1);
+        assert_eq!(set.entropy(), 0.0);
+
+        set.insert(2);
+        set.insert(2);
+        assert!((set.entropy() - 1.0).abs() < 1e-12);
+
+        set.insert(3);
+        set.insert(3);
+        set.insert(4);
+        set.insert(4);
+        assert!((set.entropy() - 2.0).abs() < 1e-12);
+
+        for _ in 0..8 {
+            set.insert(1);
+        }
+        assert!(set.entropy() > 0.0 && set.entropy() < 2.0);
+    }
+
+    #[test]
+    fn test_simpson_index() {
+        let mut set = TreeMultiSet::new();
+        assert_eq!(set.simpson_index(), 0.0);
+
+        set.insert(1);
+        set.insert(1);
+        assert_eq!(set.simpson_index(), 0.0);
+
+        set.insert(2);
+        set.insert(2);
+        assert!((set.simpson_index() - 0.5).abs() < 1e-12);
+
+        set.insert(3);
+        set.insert(3);
+        set.insert(4);
+        set.insert(4);
+        assert!((set.simpson_index() - 0.75).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_bounded_tree_multi_set() {
+        let mut set = BoundedTreeMultiSet::new(2, EvictionPolicy::Largest);
+        assert_eq!(set.insert(5), None);
+        assert_eq!(set.insert(5), None);
+        assert_eq!(set.insert(1), None);
+        assert_eq!(set.insert(1), None);
+        assert_eq!(set.insert(3), Some((5, 2)));
+        assert_eq!(set.as_set().iter().collect::<Vec<_>>(), vec![&1, &1, &3]);
+        assert_eq!(set.as_set().len(), 3);
+        assert_eq!(set.insert(9), Some((9, 1)));
+        assert_eq!(set.as_set().len(), 3);
+
+        let mut set = BoundedTreeMultiSet::new(2, EvictionPolicy::Smallest);
+        set.insert(1);
+        set.insert(1);
+        set.insert(2);
+        assert_eq!(set.insert(3), Some((1, 2)));
+        assert_eq!(set.remove_one(&2), Some(2));
+        assert_eq!(set.insert(0), None);
+        assert_eq!(set.into_inner().iter().collect::<Vec<_>>(), vec![&0, &3]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_bounded_tree_multi_set_zero_limit() {
+        BoundedTreeMultiSet::<i32>::new(0, EvictionPolicy::Smallest);
+    }
+
+    #[test]
+    fn test_count_if() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 3, 4, 4, 4] {
+            set.insert(i);
+        }
+
+        let mut calls = 0;
+        assert_eq!(set.count_if(|&x| { calls += 1; x % 2 == 0 }), 4);
+        assert_eq!(calls, 4);
+        assert_eq!(set.count_if(|&x| x > 10), 0);
+    }
+
+    #[test]
+    fn test_any_all_keys() {
+        let mut set = TreeMultiSet::new();
+        for i in [10, 50, 50, 150, 200] {
+            set.insert(i);
+        }
+
+        let mut calls = 0;
+        assert!(set.any_key(|&x| { calls += 1; x > 100 }));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        assert!(!set.all_keys(|&x| { calls += 1; x < 100 }));
+        assert_eq!(calls, 3);
+
+        assert!(set.all_keys(|&x| x >= 10));
+        assert!(!set.any_key(|&x| x > 200));
+        assert!(TreeMultiSet::<i32>::new().all_keys(|_| false));
+        assert!(!TreeMultiSet::<i32>::new().any_key(|_| true));
+    }
+
+    #[test]
+    fn test_remove_least_most_frequent() {
+        let mut set = TreeMultiSet::new();
+        assert_eq!(set.remove_least_frequent(), None);
+        assert_eq!(set.remove_most_frequent(), None);
+
+        for (k, n) in [(1, 2), (2, 3), (3, 1), (4, 3), (5, 1)] {
+            for _ in 0..n {
+                set.insert(k);
+            }
+        }
+
+        assert_eq!(set.remove_least_frequent(), Some((3, 1)));
+        assert_eq!(set.len(), 9);
+        assert_eq!(set.remove_most_frequent(), Some((2, 3)));
+        assert_eq!(set.len(), 6);
+        assert_eq!(set.remove_least_frequent(), Some((5, 1)));
+        assert_eq!(set.remove_least_frequent(), Some((1, 2)));
+        assert_eq!(set.remove_most_frequent(), Some((4, 3)));
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_extend_vec() {
+        let mut a = TreeMultiSet::new();
+        for i in [3, 1, 3] {
+            a.insert(i);
+        }
+        let mut b = TreeMultiSet::new();
+        b.insert(2);
+
+        let mut out = vec![9, 8];
+        a.extend_vec(&mut out);
+        b.extend_vec(&mut out);
+        assert_eq!(out, vec![9, 8, 1, 3, 3, 2]);
+
+        TreeMultiSet::new().extend_vec(&mut out);
+        assert_eq!(out.len(), 6);
+    }
+
+    #[test]
+    fn test_distinct_above_below() {
+        let mut set = TreeMultiSet::new();
+        for (k, n) in [(1, 1), (2, 2), (3, 3), (4, 3), (5, 5)] {
+            for _ in 0..n {
+                set.insert(k);
+            }
+        }
+
+        assert_eq!(set.distinct_above(0), 5);
+        assert_eq!(set.distinct_above(2), 3);
+        assert_eq!(set.distinct_above(3), 1);
+        assert_eq!(set.distinct_above(5), 0);
+        assert_eq!(set.distinct_below(1), 0);
+        assert_eq!(set.distinct_below(3), 2);
+        assert_eq!(set.distinct_below(6), 5);
+    }
+
+    #[test]
+    fn test_merge_max_min() {
+        let mut a = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 3, 3] {
+            a.insert(i);
+        }
+        let mut b = TreeMultiSet::new();
+        for i in [2, 2, 2, 3, 4] {
+            b.insert(i);
+        }
+
+        let mut max = TreeMultiSet::new();
+        max.insert_all_from(&a);
+        max.merge_max(&b);
+        assert_eq!(max.iter().collect::<Vec<_>>(), vec![&1, &2, &2, &2, &3, &3, &3, &4]);
+        assert_eq!(max.len(), 8);
+
+        let mut min = TreeMultiSet::new();
+        min.insert_all_from(&a);
+        min.merge_min(&b);
+        assert_eq!(min.iter().collect::<Vec<_>>(), vec![&2, &2, &3]);
+        assert_eq!(min.len(), 3);
+    }
+
+    #[test]
+    fn test_pop_with_count() {
+        let mut set = TreeMultiSet::new();
+        assert_eq!(set.pop_first_with_count(), None);
+        assert_eq!(set.pop_last_with_count(), None);
+
+        for i in [1, 1, 2, 3, 3] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.pop_first_with_count(), Some((1, 1)));
+        assert_eq!(set.pop_first_with_count(), Some((1, 0)));
+        assert_eq!(set.pop_last_with_count(), Some((3, 1)));
+        assert_eq!(set.pop_last_with_count(), Some((3, 0)));
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.pop_last_with_count(), Some((2, 0)));
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_contains_exactly() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.insert(1);
+
+        assert!(set.contains_exactly(&1, 2));
+        assert!(!set.contains_exactly(&1, 1));
+        assert!(!set.contains_exactly(&1, 0));
+        assert!(set.contains_exactly(&2, 0));
+        assert!(!set.contains_exactly(&2, 1));
+    }
+
+    #[test]
+    fn test_first_last_rank() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.first_rank(&1), Some(0));
+        assert_eq!(set.last_rank(&1), Some(0));
+        assert_eq!(set.first_rank(&2), Some(1));
+        assert_eq!(set.last_rank(&2), Some(2));
+        assert_eq!(set.first_rank(&3), Some(3));
+        assert_eq!(set.last_rank(&3), Some(3));
+        assert_eq!(set.first_rank(&4), None);
+        assert_eq!(set.last_rank(&0), None);
+    }
+
+    #[test]
+    fn test_iter_indexed() {
+        let mut set = TreeMultiSet::new();
+        for i in [2, 1, 2, 3] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.iter_indexed().collect::<Vec<_>>(), vec![(0, &1), (1, &2), (2, &2), (3, &3)]);
+        assert!(set.iter_indexed().map(|(i, _)| i).eq(0..set.len()));
+    }
+
+    #[test]
+    fn test_remove_nth() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.remove_nth(4), None);
+        assert_eq!(set.remove_nth(1), Some(2));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &2, &3]);
+        assert_eq!(set.remove_nth(2), Some(3));
+        assert_eq!(set.remove_nth(0), Some(1));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&2]);
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn test_winsorize() {
+        let mut set = TreeMultiSet::new();
+        for i in 0..=10 {
+            set.insert(i);
+        }
+
+        set.winsorize(0.2, 0.7);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&2, &2, &2, &3, &4, &5, &6, &7, &7, &7, &7]);
+        assert_eq!(set.len(), 11);
+
+        set.winsorize(-1.0, 2.0);
+        assert_eq!(set.len(), 11);
+        assert_eq!(set.first(), Some(&2));
+        assert_eq!(set.last(), Some(&7));
+
+        set.winsorize(0.5, 0.5);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&5; 11]);
+        assert_eq!(set.len(), 11);
+
+        let mut empty = TreeMultiSet::<i32>::new();
+        empty.winsorize(0.1, 0.9);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_winsorize_inverted_quantiles() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.winsorize(0.9, 0.1);
+    }
+
+    #[test]
+    fn test_slide() {
+        let stream = [5, 1, 4, 4, 2, 8, 3];
+        let mut window = TreeMultiSet::new();
+        for &x in &stream[..3] {
+            window.insert(x);
+        }
+
+        for i in 3..stream.len() {
+            assert!(window.slide(stream[i], &stream[i - 3]));
+            assert_eq!(window.len(), 3);
+            let mut expected = stream[i - 2..=i].to_vec();
+            expected.sort();
+            assert_eq!(window.iter().cloned().collect::<Vec<_>>(), expected);
+        }
+
+        assert!(!window.slide(7, &100));
+        assert_eq!(window.len(), 4);
+        assert!(window.slide(7, &7));
+        assert_eq!(window.count(&7), 1);
+    }
+
+    #[test]
+    fn test_rank_of_value_sequence() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 5] {
+            set.insert(i);
+        }
+
+        let values = [3, 0, 2, 6, 4, 2, 1];
+        let ranks = set.rank_of_value_sequence(&values);
+        assert_eq!(ranks, vec![3, 0, 1, 5, 4, 1, 0]);
+        assert_eq!(ranks, values.iter().map(|v| set.equal_range(v).0).collect::<Vec<_>>());
+        assert!(set.rank_of_value_sequence(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_fold_range() {
+        let mut set = TreeMultiSet::new();
+        for i in 1..=6 {
+            for _ in 0..i {
+                set.insert(i);
+            }
+        }
+
+        assert_eq!(set.fold_range(2..5, 0, |acc, _, c| acc + c), set.count_range_of(2..5).1);
+        assert_eq!(set.fold_range(2..5, 0, |acc, _, c| acc + c), 9);
+        assert_eq!(set.fold_range(..=2, Vec::new(), |mut acc, &k, c| { acc.push((k, c)); acc }), vec![(1, 1), (2, 2)]);
+        assert_eq!(set.fold_range(7.., 0, |acc, _, c| acc + c), 0);
+    }
+
+    #[cfg(feature = "debug-invariants")]
+    #[test]
+    fn test_assert_invariants() {
+        let mut set = TreeMultiSet::new();
+        set.assert_invariants();
+        for i in [1, 2, 2, 3] {
+            set.insert(i);
+        }
+        set.remove_one(&2);
+        set.assert_invariants();
+    }
+
+    #[cfg(feature = "debug-invariants")]
+    #[test]
+    #[should_panic]
+    fn test_assert_invariants_detects_desync() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.count = 2;
+        set.assert_invariants();
+    }
+
+    #[test]
+    fn test_to_count_map() {
+        use std::collections::HashMap;
+
+        let mut set = TreeMultiSet::new();
+        for i in [3, 1, 3, 2, 3] {
+            set.insert(i);
+        }
+
+        let map: HashMap<i32, usize> = set.to_count_map();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[&1], 1);
+        assert_eq!(map[&3], 3);
+
+        let pairs: Vec<(i32, usize)> = set.to_count_map();
+        assert_eq!(pairs, vec![(1, 1), (2, 1), (3, 3)]);
+    }
+
+    #[test]
+    fn test_merge_all() {
+        let mut shards = Vec::new();
+        for items in [vec![1, 2, 2], vec![], vec![2, 3], vec![1, 4, 4, 4]] {
+            let mut set = TreeMultiSet::new();
+            for i in items {
+                set.insert(i);
+            }
+            shards.push(set);
+        }
+
+        let merged = TreeMultiSet::merge_all(&shards);
+        assert_eq!(merged.iter().collect::<Vec<_>>(), vec![&1, &1, &2, &2, &2, &3, &4, &4, &4]);
+        assert_eq!(merged.len(), shards.iter().map(|s| s.len()).sum::<usize>());
+        assert!(TreeMultiSet::<i32>::merge_all([]).is_empty());
+    }
+
+    #[test]
+    fn test_distinct_count_where() {
+        let mut set = TreeMultiSet::new();
+        for i in [2, 2, 4, 5] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.distinct_count_where(|&x| x % 2 == 0), 2);
+        assert_eq!(set.count_if(|&x| x % 2 == 0), 3);
+        assert_eq!(set.distinct_count_where(|&x| x > 5), 0);
+    }
+
+    #[test]
+    fn test_into_btree_set() {
+        let mut set = TreeMultiSet::new();
+        for i in [3, 1, 3, 2, 3] {
+            set.insert(i);
+        }
+        let distinct = set.counts().len();
+
+        let keys = set.into_btree_set();
+        assert_eq!(keys.len(), distinct);
+        assert_eq!(keys.into_iter().collect::<Vec<_>>(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_from_btree_set() {
+        let keys: BTreeSet<i32> = [3, 1, 2].into_iter().collect();
+
+        let set = TreeMultiSet::from_btree_set(keys.clone(), 2);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &1, &2, &2, &3, &3]);
+        assert_eq!(set.len(), 6);
+
+        let set = TreeMultiSet::from_btree_set(keys, 0);
+        assert!(set.is_empty());
+        assert_eq!(set.iter().next(), None);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut set = TreeMultiSet::new();
+        let snap = set.snapshot();
+        assert!(!set.changed_since(&snap));
+
+        set.insert(1);
+        assert!(set.changed_since(&snap));
+
+        let snap = set.snapshot();
+        assert!(!set.changed_since(&snap));
+        assert_eq!(set.remove_one(&2), None);
+        assert!(!set.changed_since(&snap));
+
+        set.insert(2);
+        set.remove_one(&2);
+        assert_eq!(set.len(), 1);
+        assert!(set.changed_since(&snap));
+
+        let snap = set.snapshot();
+        *set.count_mut_or_insert(1, 0) += 1;
+        assert!(set.changed_since(&snap));
+    }
+
+    #[test]
+    fn test_add_to_range() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 4, 6] {
+            set.insert(i);
+        }
+
+        set.add_to_range(2..=5, 2);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &2, &2, &2, &2, &4, &4, &4, &6]);
+        assert_eq!(set.len(), 9);
+        assert!(!set.contains(&3));
+        assert_eq!(set.count(&1), 1);
+        assert_eq!(set.count(&6), 1);
+
+        set.add_to_range(7.., 5);
+        assert_eq!(set.len(), 9);
+    }
+
+    #[test]
+    fn test_remove_in_range() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 3, 3, 5, 7] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.remove_one_in_range(2..6), Some(3));
+        assert_eq!(set.remove_last_in_range(2..6), Some(5));
+        assert_eq!(set.remove_last_in_range(2..6), Some(3));
+        assert_eq!(set.remove_one_in_range(2..6), None);
+        assert_eq!(set.remove_last_in_range(..), Some(7));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn test_count_eq() {
+        let mut a = TreeMultiSet::new();
+        let mut b = TreeMultiSet::new();
+        assert!(a.count_eq(&b));
+
+        for i in [1, 2, 2, 3] {
+            a.insert(i);
+        }
+        for i in [3, 2, 1, 2] {
+            b.insert(i);
+        }
+        assert!(a.count_eq(&b));
+
+        b.remove_one(&2);
+        b.insert(1);
+        assert_eq!(a.len(), b.len());
+        assert!(!a.count_eq(&b));
+
+        b.remove_one(&1);
+        assert!(!a.count_eq(&b));
+    }
+
+    #[test]
+    fn test_decay() {
+        let mut set = TreeMultiSet::new();
+        for (k, n) in [(1, 1), (2, 2), (3, 5), (4, 8)] {
+            for _ in 0..n {
+                set.insert(k);
+            }
+        }
+
+        set.decay(1, 2);
+        assert_eq!(set.count(&1), 0);
+        assert!(!set.contains(&1));
+        assert_eq!(set.count(&2), 1);
+        assert_eq!(set.count(&3), 2);
+        assert_eq!(set.count(&4), 4);
+        assert_eq!(set.len(), 7);
+
+        set.decay(3, 2);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&2, &3, &3, &3, &4, &4, &4, &4, &4, &4]);
+        assert_eq!(set.len(), 10);
+
+        set.decay(0, 1);
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_decay_zero_denominator() {
+        let mut set = TreeMultiSet::new();
+        set.insert(1);
+        set.decay(1, 0);
+    }
+
+    #[test]
+    fn test_pairwise_gaps() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 3, 3, 5, 6, 10] {
+            set.insert(i);
+        }
+
+        let gaps = set.pairwise_gaps();
+        assert_eq!(gaps.iter().collect::<Vec<_>>(), vec![&1, &2, &2, &4]);
+        assert_eq!(gaps.len(), 4);
+
+        let mut single = TreeMultiSet::new();
+        single.insert(1);
+        single.insert(1);
+        assert!(single.pairwise_gaps().is_empty());
+    }
+
+    #[test]
+    fn test_clamp() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 4, 5, 7, 9, 9] {
+            set.insert(i);
+        }
+
+        set.clamp(3, 7);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&3, &3, &3, &4, &5, &7, &7, &7]);
+        assert_eq!(set.len(), 8);
+
+        set.clamp(4, 6);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&4, &4, &4, &4, &5, &6, &6, &6]);
+        assert_eq!(set.len(), 8);
+
+        set.clamp(5, 5);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&5; 8]);
+        let before = set.mutation_count();
+        set.clamp(5, 5);
+        assert_eq!(set.mutation_count(), before);
+
+        set.clamp(0, 10);
+        assert_eq!(set.count(&5), 8);
+        assert!(!set.contains(&0));
+        assert!(!set.contains(&10));
+    }
+
+    #[test]
+    fn test_run_count() {
+        let mut set = TreeMultiSet::new();
+        assert_eq!(set.run_count(), 0);
 
-        assert_eq!(set.count(&1), 1);
-        assert_eq!(set.count(&2), 1);
-        assert_eq!(set.count(&3), 2);
-        assert_eq!(set.count(&4), 2);
-        assert_eq!(set.count(&5), 1);
-        assert_eq!(set.count(&6), 0);
+        for i in [1, 2, 2, 3, 4, 4, 4] {
+            set.insert(i);
+        }
+        assert_eq!(set.run_count(), 2);
+
+        set.remove_one(&2);
+        assert_eq!(set.run_count(), 1);
     }
 
     #[test]
-    fn test_empty_test() {
-        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
-        assert!(set.is_empty());
-        assert_eq!(set.len(), 0);
-        assert_eq!(set.first(), None);
-        assert_eq!(set.last(), None);
-        assert_eq!(set.pop_first(), None);
-        assert_eq!(set.pop_last(), None);
-        assert_eq!(set.iter().next(), None);
-        assert_eq!(set.range(0..10).next(), None);
-        assert_eq!(set.range(..).next(), None);
+    fn test_iter_run_indexed() {
+        let mut set = TreeMultiSet::new();
+        for i in [3, 2, 2, 5, 5, 5] {
+            set.insert(i);
+        }
+
+        assert_eq!(
+            set.iter_run_indexed().collect::<Vec<_>>(),
+            vec![(&2, 0), (&2, 1), (&3, 0), (&5, 0), (&5, 1), (&5, 2)]
+        );
     }
 
     #[test]
-    fn test_insert_remove() {
-        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
-        set.insert(1);
-        set.insert(2);
-        set.insert(3);
-        set.insert(4);
-        set.insert(4);
-        set.insert(5);
-        set.insert(3);
+    fn test_mode_in_range() {
+        let mut set = TreeMultiSet::new();
+        for (k, n) in [(1, 5), (2, 2), (3, 3), (4, 3), (6, 1)] {
+            for _ in 0..n {
+                set.insert(k);
+            }
+        }
 
-        assert_eq!(set.len(), 7);
-        assert_eq!(set.first(), Some(&1));
-        assert_eq!(set.last(), Some(&5));
-        assert_eq!(set.pop_first(), Some(1));
-        assert_eq!(set.first(), Some(&2));
-        assert_eq!(set.len(), 6);
-        assert_eq!(set.pop_last(), Some(5));
-        assert_eq!(set.last(), Some(&4));
-        assert_eq!(set.len(), 5);
+        assert_eq!(set.mode_in_range(..), Some(&1));
+        assert_eq!(set.mode_in_range(2..), Some(&3));
+        assert_eq!(set.mode_in_range(4..), Some(&4));
+        assert_eq!(set.mode_in_range(2..3), Some(&2));
+        assert_eq!(set.mode_in_range(7..), None);
+    }
 
-        assert_eq!(set.remove_one(&3), Some(3));
-        assert_eq!(set.len(), 4);
-        assert_eq!(set.remove_one(&3), Some(3));
-        assert_eq!(set.len(), 3);
-        assert_eq!(set.remove_one(&3), None);
+    #[test]
+    fn test_count_capped() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 1, 1, 2] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.count_capped(&1, 2), 2);
+        assert_eq!(set.count_capped(&1, 5), 3);
+        assert_eq!(set.count_capped(&2, 2), 1);
+        assert_eq!(set.count_capped(&3, 2), 0);
+    }
+
+    #[test]
+    fn test_insert_run_ingest_sorted() {
+        let mut set = TreeMultiSet::new();
+        set.insert_run(2, 3);
+        set.insert_run(1, 0);
+        assert!(!set.contains(&1));
         assert_eq!(set.len(), 3);
 
-        assert_eq!(set.remove_all(&4), Some(4));
+        let snap = set.snapshot();
+        set.ingest_sorted([1, 1, 2, 4, 4, 4, 4].into_iter());
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &1, &2, &2, &2, &2, &4, &4, &4, &4]);
+        assert_eq!(set.len(), 10);
+        assert!(set.changed_since(&snap));
+
+        set.ingest_sorted([5, 1, 5].into_iter());
+        assert_eq!(set.count(&5), 2);
+        assert_eq!(set.count(&1), 3);
+        assert_eq!(set.len(), 13);
+    }
+
+    #[test]
+    fn test_pop_while() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 5, 8, 8] {
+            set.insert(i);
+        }
+
+        assert_eq!(set.pop_first_while(|&x| x < 3), vec![1, 2, 2]);
+        assert_eq!(set.len(), 4);
+        assert_eq!(set.pop_first_while(|&x| x < 3), Vec::<i32>::new());
+        assert_eq!(set.pop_last_while(|&x| x > 4), vec![8, 8, 5]);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&3]);
         assert_eq!(set.len(), 1);
-        assert_eq!(set.remove_all(&4), None);
+        assert_eq!(set.pop_last_while(|_| true), vec![3]);
+        assert!(set.is_empty());
+    }
 
-        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&2]);
+    #[test]
+    fn test_has_at_least() {
+        let mut set = TreeMultiSet::new();
+        assert!(set.has_at_least_total(0));
+        assert!(!set.has_at_least_total(1));
+        assert!(set.has_at_least_distinct(0));
+
+        for i in [1, 1, 2] {
+            set.insert(i);
+        }
+        assert!(set.has_at_least_total(3));
+        assert!(!set.has_at_least_total(4));
+        assert!(set.has_at_least_distinct(2));
+        assert!(!set.has_at_least_distinct(3));
     }
 
     #[test]
-    fn test_contains() {
-        let mut set: TreeMultiSet<i32> = TreeMultiSet::new();
-        set.insert(1);
-        set.insert(2);
-        set.insert(3);
-        set.insert(4);
-        set.insert(4);
-        set.insert(5);
+    fn test_merge_with() {
+        let mut a = TreeMultiSet::new();
+        for i in [1, 2, 2, 3] {
+            a.insert(i);
+        }
+        let mut b = TreeMultiSet::new();
+        for i in [2, 3, 3, 3, 4] {
+            b.insert(i);
+        }
 
-        assert!(set.contains(&1));
-        assert!(set.contains(&2));
-        assert!(set.contains(&3));
-        assert!(set.contains(&4));
-        assert!(set.contains(&5));
+        let sum = a.merge_with(&b, |_, x, y| x + y);
+        assert_eq!(sum.iter().collect::<Vec<_>>(), vec![&1, &2, &2, &2, &3, &3, &3, &3, &4]);
+        assert_eq!(sum.len(), 9);
 
-        assert!(!set.contains(&6));
-        assert!(!set.contains(&7));
+        let min = a.merge_with(&b, |_, x, y| x.min(y));
+        assert_eq!(min.iter().collect::<Vec<_>>(), vec![&2, &3]);
+        assert_eq!(min.len(), 2);
+
+        let diff = a.merge_with(&b, |_, x, y| x.saturating_sub(y));
+        assert_eq!(diff.iter().collect::<Vec<_>>(), vec![&1, &2]);
+
+        let keyed = a.merge_with(&b, |&k, x, y| if k % 2 == 0 { x.max(y) } else { 0 });
+        assert_eq!(keyed.iter().collect::<Vec<_>>(), vec![&2, &2, &4]);
+        assert_eq!(keyed.len(), 3);
     }
 
     #[test]
-    fn test_iter() {
+    fn test_distinct_total_between() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 5, 5, 5] {
+            set.insert(i);
+        }
+        assert_eq!(set.distinct_between(&2, &5), 3);
+        assert_eq!(set.total_between(&2, &5), 6);
+        assert_eq!(set.distinct_between(&4, &4), 0);
+        assert_eq!(set.total_between(&5, &5), 3);
+        assert_eq!(set.distinct_between(&5, &1), 0);
+        assert_eq!(set.total_between(&5, &1), 0);
+    }
+
+    #[test]
+    fn test_nth_distinct() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 1, 2, 3] {
+            set.insert(i);
+        }
+        assert_eq!(set.nth_distinct(0), Some(&1));
+        assert_eq!(set.nth_distinct(1), Some(&2));
+        assert_eq!(set.nth_distinct(2), Some(&3));
+        assert_eq!(set.nth_distinct(3), None);
+        assert_eq!(TreeMultiSet::<i32>::new().nth_distinct(0), None);
+    }
+
+    #[test]
+    fn test_mutation_count() {
         let mut set = TreeMultiSet::new();
+        assert_eq!(set.mutation_count(), 0);
+        set.insert(1);
         set.insert(1);
+        assert_eq!(set.mutation_count(), 2);
+        set.ingest_sorted([2, 2, 3, 4].into_iter());
+        assert_eq!(set.mutation_count(), 3);
+        assert!(set.slide(5, &1));
+        assert_eq!(set.mutation_count(), 4);
+        set.replace_range(2..4, [6, 7]);
+        assert_eq!(set.mutation_count(), 5);
+        assert_eq!(set.remove_one(&100), None);
+        assert_eq!(set.mutation_count(), 5);
+        set.clear();
+        assert_eq!(set.mutation_count(), 6);
+        let _ = set.len();
+        assert_eq!(set.mutation_count(), 6);
+
+        let mut bounded = BoundedTreeMultiSet::new(1, EvictionPolicy::Smallest);
+        bounded.insert(1);
+        assert_eq!(bounded.insert(2), Some((1, 1)));
+        assert_eq!(bounded.as_set().mutation_count(), 2);
+    }
+
+    #[test]
+    fn test_retain_in_remove_in() {
+        let mut set = TreeMultiSet::new();
+        let mut kept = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 4, 4, 4, 6] {
+            set.insert(i);
+            kept.insert(i);
+        }
+        let keys: BTreeSet<i32> = [0, 2, 4, 5].into_iter().collect();
+
+        kept.retain_in(&keys);
+        assert_eq!(kept.iter().collect::<Vec<_>>(), vec![&2, &2, &4, &4, &4]);
+        assert_eq!(kept.len(), 5);
+
+        set.remove_in(&keys);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &3, &6]);
+        assert_eq!(set.len(), 3);
+
+        set.retain_in(&BTreeSet::new());
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_keys_only_in_self() {
+        let mut a = TreeMultiSet::new();
+        for i in [1, 1, 2, 3, 5, 5] {
+            a.insert(i);
+        }
+        let mut b = TreeMultiSet::new();
+        for i in [0, 2, 4, 5] {
+            b.insert(i);
+        }
+        assert_eq!(a.keys_only_in_self(&b), vec![&1, &3]);
+        assert_eq!(b.keys_only_in_self(&a), vec![&0, &4]);
+        assert!(a.keys_only_in_self(&a).is_empty());
+        assert_eq!(a.keys_only_in_self(&TreeMultiSet::new()), vec![&1, &2, &3, &5]);
+    }
+
+    #[test]
+    fn test_weighted_merge() {
+        let mut a = TreeMultiSet::new();
+        for i in [1, 2, 2] {
+            a.insert(i);
+        }
+        let mut b = TreeMultiSet::new();
+        for i in [2, 3, 3] {
+            b.insert(i);
+        }
+
+        let merged = a.weighted_merge(2, &b, 3);
+        assert_eq!(merged.count(&1), 2);
+        assert_eq!(merged.count(&2), 7);
+        assert_eq!(merged.count(&3), 6);
+        assert_eq!(merged.len(), 15);
+
+        let only_self = a.weighted_merge(1, &b, 0);
+        assert_eq!(only_self.iter().collect::<Vec<_>>(), vec![&1, &2, &2]);
+        assert_eq!(only_self.len(), 3);
+
+        assert!(a.weighted_merge(0, &b, 0).is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_weighted_merge_overflow() {
+        let mut a = TreeMultiSet::new();
+        a.insert_run(1, 2);
+        a.weighted_merge(usize::MAX, &TreeMultiSet::new(), 1);
+    }
+
+    #[test]
+    fn test_quantile_rank() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3] {
+            set.insert(i);
+        }
+        assert_eq!(set.quantile_rank(&0), 0.0);
+        assert_eq!(set.quantile_rank(&1), 0.25);
+        assert_eq!(set.quantile_rank(&2), 0.75);
+        assert_eq!(set.quantile_rank(&3), 1.0);
+        assert_eq!(set.quantile_rank(&10), 1.0);
+        assert_eq!(TreeMultiSet::<i32>::new().quantile_rank(&1), 0.0);
+    }
+
+    #[test]
+    fn test_try_from_pairs() {
+        let set = TreeMultiSet::try_from_pairs(vec![(3, 1), (1, 2), (2, 3)]).unwrap();
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &1, &2, &2, &2, &3]);
+        assert_eq!(set.len(), 6);
+
+        assert!(TreeMultiSet::<i32>::try_from_pairs(vec![]).unwrap().is_empty());
+        assert_eq!(TreeMultiSet::try_from_pairs(vec![(1, 2), (2, 0)]).err(), Some(BuildError::ZeroCount { index: 1 }));
+        assert_eq!(TreeMultiSet::try_from_pairs(vec![(1, 2), (2, 1), (1, 1)]).err(), Some(BuildError::DuplicateKey { index: 2 }));
+        assert_eq!(BuildError::ZeroCount { index: 1 }.to_string(), "pair 1 has a count of zero");
+    }
+
+    #[test]
+    fn test_iter_counts_rev() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3] {
+            set.insert(i);
+        }
+        assert_eq!(set.iter_counts_rev().collect::<Vec<_>>(), vec![(&3, 1), (&2, 2), (&1, 1)]);
+        assert_eq!(TreeMultiSet::<i32>::new().iter_counts_rev().count(), 0);
+    }
+
+    #[test]
+    fn test_count_where_in_range() {
+        let mut set = TreeMultiSet::new();
+        for i in [5, 10, 10, 12, 13, 40, 50, 50, 60] {
+            set.insert(i);
+        }
+        assert_eq!(set.count_where_in_range(10..=50, |&x| x % 2 == 0), 6);
+        assert_eq!(set.count_where_in_range(11..40, |&x| x % 2 == 1), 1);
+        assert_eq!(set.count_where_in_range(20..30, |_| true), 0);
+        assert_eq!(set.count_where_in_range(.., |_| false), 0);
+    }
+
+    #[test]
+    fn test_to_rle_from_rle() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3, 3, 3] {
+            set.insert(i);
+        }
+        let rle = set.to_rle();
+        assert_eq!(rle, vec![(1, 1), (2, 2), (3, 3)]);
+
+        let back = TreeMultiSet::from_rle(rle).unwrap();
+        assert_eq!(back.iter().collect::<Vec<_>>(), set.iter().collect::<Vec<_>>());
+        assert_eq!(back.len(), 6);
+
+        assert!(TreeMultiSet::<i32>::from_rle(vec![]).unwrap().is_empty());
+        assert_eq!(TreeMultiSet::from_rle(vec![(1, 1), (2, 0)]).err(), Some(BuildError::ZeroCount { index: 1 }));
+        assert_eq!(TreeMultiSet::from_rle(vec![(1, 1), (1, 2)]).err(), Some(BuildError::DuplicateKey { index: 1 }));
+        assert_eq!(TreeMultiSet::from_rle(vec![(1, 1), (3, 1), (2, 1)]).err(), Some(BuildError::Unsorted { index: 2 }));
+    }
+
+    #[test]
+    fn test_rank_of_occurrence() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3] {
+            set.insert(i);
+        }
+        assert_eq!(set.rank_of_occurrence(&1, 0), Some(0));
+        assert_eq!(set.rank_of_occurrence(&2, 0), Some(1));
+        assert_eq!(set.rank_of_occurrence(&2, 1), Some(2));
+        assert_eq!(set.rank_of_occurrence(&2, 2), None);
+        assert_eq!(set.rank_of_occurrence(&3, 0), Some(3));
+        assert_eq!(set.rank_of_occurrence(&4, 0), None);
+    }
+
+    #[test]
+    fn test_count_tied_at_rank() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3] {
+            set.insert(i);
+        }
+        assert_eq!(set.count_tied_at_rank(0), 1);
+        assert_eq!(set.count_tied_at_rank(1), 2);
+        assert_eq!(set.count_tied_at_rank(2), 2);
+        assert_eq!(set.count_tied_at_rank(3), 1);
+        assert_eq!(set.count_tied_at_rank(4), 0);
+    }
+
+    #[test]
+    fn test_bisect() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 3] {
+            set.insert(i);
+        }
+        assert_eq!(set.bisect(&0), (0, 0));
+        assert_eq!(set.bisect(&1), (0, 1));
+        assert_eq!(set.bisect(&2), (1, 3));
+        assert_eq!(set.bisect(&3), (3, 4));
+        assert_eq!(set.bisect(&4), (4, 4));
+    }
+
+    #[test]
+    fn test_compress() {
+        let mut set = TreeMultiSet::new();
+        for i in [30, 10, 20, 10, 30] {
+            set.insert(i);
+        }
+        let compressed = set.compress();
+        assert_eq!(compressed.into_iter().collect::<Vec<_>>(), vec![(10, 0), (20, 1), (30, 2)]);
+        assert!(TreeMultiSet::<i32>::new().compress().is_empty());
+    }
+
+    #[test]
+    fn test_sum_of_top_k() {
+        let mut set = TreeMultiSet::new();
+        for i in [1i64, 2, 2, 3, 5, 5] {
+            set.insert(i);
+        }
+        assert_eq!(set.sum_of_top_k(0), 0);
+        assert_eq!(set.sum_of_top_k(1), 5);
+        assert_eq!(set.sum_of_top_k(3), 13);
+        assert_eq!(set.sum_of_top_k(4), 15);
+        assert_eq!(set.sum_of_top_k(6), 18);
+        assert_eq!(set.sum_of_top_k(100), 18);
+        assert_eq!(TreeMultiSet::<u32>::new().sum_of_top_k(3), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_sum_of_top_k_count_not_representable() {
+        let mut set = TreeMultiSet::new();
+        set.insert_run(1u8, 300);
+        set.sum_of_top_k(300);
+    }
+
+    #[test]
+    fn test_sum_of_range() {
+        let mut set = TreeMultiSet::new();
+        for i in [1i32, 2, 2, 3] {
+            set.insert(i);
+        }
+        assert_eq!(set.sum_of_range(2..=3), 7);
+        assert_eq!(set.sum_of_range(..2), 1);
+        assert_eq!(set.sum_of_range(..), 8);
+        assert_eq!(set.sum_of_range(4..), 0);
+    }
+
+    #[test]
+    fn test_equal_pair_count() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 1, 1, 2] {
+            set.insert(i);
+        }
+        assert_eq!(set.equal_pair_count(), 3);
         set.insert(2);
+        assert_eq!(set.equal_pair_count(), 4);
+        assert_eq!(TreeMultiSet::<i32>::new().equal_pair_count(), 0);
+
+        let mut big = TreeMultiSet::new();
+        big.insert_run(0, 1 << 20);
+        assert_eq!(big.equal_pair_count(), (1 << 19) * ((1 << 20) - 1));
+    }
+
+    #[test]
+    fn test_distinct_value_pair_count() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 1, 1, 2] {
+            set.insert(i);
+        }
+        assert_eq!(set.distinct_value_pair_count(), 3);
         set.insert(3);
-        set.insert(3);
-        set.insert(4);
-        set.insert(5);
-        set.insert(5);
+        assert_eq!(set.distinct_value_pair_count(), 7);
+        assert_eq!(TreeMultiSet::<i32>::new().distinct_value_pair_count(), 0);
 
-        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &2, &3, &3, &4, &5, &5]);
+        let mut single = TreeMultiSet::new();
+        single.insert_run(5, 10);
+        assert_eq!(single.distinct_value_pair_count(), 0);
     }
 
     #[test]
-    fn test_rev_iter() {
+    fn test_split_into_buckets() {
+        let mut set = TreeMultiSet::new();
+        for i in [1, 2, 2, 5, 7, 7, 10, 12] {
+            set.insert(i);
+        }
+        let buckets = set.split_into_buckets(&[2, 7, 7, 11]);
+        let contents: Vec<Vec<&i32>> = buckets.iter().map(|b| b.iter().collect()).collect();
+        assert_eq!(contents, vec![vec![&1], vec![&2, &2, &5], vec![], vec![&7, &7, &10], vec![&12]]);
+        assert_eq!(buckets.iter().map(|b| b.len()).sum::<usize>(), set.len());
+
+        let whole = set.split_into_buckets(&[]);
+        assert_eq!(whole.len(), 1);
+        assert_eq!(whole[0].len(), 8);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_split_into_buckets_unsorted() {
         let mut set = TreeMultiSet::new();
         set.insert(1);
-        set.insert(2);
-        set.insert(3);
-        set.insert(3);
-        set.insert(4);
-        set.insert(5);
-        set.insert(5);
+        set.split_into_buckets(&[5, 3]);
     }
 
     #[test]
-    fn test_range() {
+    fn test_mutation_count_no_ops() {
+        let mut set = TreeMultiSet::<i32>::new();
+        set.clear();
+        set.pop_first_while(|_| true);
+        set.pop_last_while(|_| true);
+        set.ingest_sorted(std::iter::empty());
+        set.apply_diff(Vec::new());
+        set.retain_range(0..10);
+        set.insert_all_from(&TreeMultiSet::new());
+        assert_eq!(set.mutation_count(), 0);
+
+        set.insert_run(1, 1);
+        set.insert(5);
+        let snap = set.snapshot();
+        let empty = TreeMultiSet::new();
+        set.pop_first_while(|_| false);
+        set.add_to_range(2..5, 1);
+        set.retain_range(..);
+        set.subtract(&empty);
+        set.intersect_with(&set.merge_with(&empty, |_, a, _| a));
+        set.merge_max(&empty);
+        set.clamp(0, 10);
+        set.clamp(1, 5);
+        set.winsorize(0.0, 1.0);
+        set.decay(1, 1);
+        set.cap_counts(2);
+        set.set_all_counts(1);
+        set.keep_every_nth_distinct(1);
+        set.keep_one_each_in_range(..);
+        set.replace_range(10.., Vec::new());
+        set.retain_in(&[1, 5].into_iter().collect());
+        set.remove_in(&BTreeSet::new());
+        *set.count_mut_or_insert(1, 0) += 0;
+        assert!(!set.changed_since(&snap));
+        assert_eq!(set.mutation_count(), 2);
+
+        set.decay(2, 1);
+        assert_eq!(set.mutation_count(), 3);
+        *set.count_mut_or_insert(7, 1) += 0;
+        assert_eq!(set.mutation_count(), 4);
+        assert_eq!(set.count(&7), 1);
+
         let mut set = TreeMultiSet::new();
-        for i in 1..=4 {
-            for _ in 0..i {
-                set.insert(i);
-            }
+        for i in [1, 2, 5] {
+            set.insert(i);
         }
+        let snap = set.snapshot();
+        set.clamp(0, 5);
+        set.clamp(1, 5);
+        set.winsorize(0.0, 1.0);
+        assert!(!set.changed_since(&snap));
+        assert_eq!(set.mutation_count(), 3);
+        set.clamp(2, 5);
+        assert_eq!(set.mutation_count(), 4);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&2, &2, &5]);
+    }
 
-        assert_eq!(set.range(1..=4).collect::<Vec<_>>(), vec![&1, &2, &2, &3, &3, &3, &4, &4, &4, &4]);
-        assert_eq!(set.range(1..3).collect::<Vec<_>>(), vec![&1, &2, &2]);
-        assert_eq!(set.range(..3).collect::<Vec<_>>(), vec![&1, &2, &2]);
-        assert_eq!(set.range(..=3).collect::<Vec<_>>(), vec![&1, &2, &2, &3, &3, &3]);
-        assert_eq!(set.range(..=3).rev().collect::<Vec<_>>(), vec![&3, &3, &3, &2, &2, &1]);
-        assert_eq!(set.range(2..).collect::<Vec<_>>(), vec![&2, &2, &3, &3, &3, &4, &4, &4, &4]);
-        assert_eq!(set.range(2..=4).rev().collect::<Vec<_>>(), vec![&4, &4, &4, &4, &3, &3, &3, &2, &2]);
+    #[test]
+    fn test_mutation_count_constructors() {
+        let mut a = TreeMultiSet::new();
+        for i in [1, 2, 2, 4, 7] {
+            a.insert(i);
+        }
+        assert_eq!(TreeMultiSet::from_sorted_iters(vec![vec![1, 2, 3], vec![2, 4]]).mutation_count(), 0);
+        assert_eq!(TreeMultiSet::merge_all([&a, &a]).mutation_count(), 0);
+        assert_eq!(a.pairwise_gaps().mutation_count(), 0);
+        assert_eq!(a.pairwise_gaps().iter().collect::<Vec<_>>(), vec![&1, &2, &3]);
+        assert_eq!(TreeMultiSet::from_btree_set(BTreeSet::from([1, 2]), 2).mutation_count(), 0);
+        assert_eq!(TreeMultiSet::try_from_pairs(vec![(1, 2)]).unwrap().mutation_count(), 0);
+        assert_eq!(TreeMultiSet::from_rle(vec![(1, 2)]).unwrap().mutation_count(), 0);
+        assert_eq!(a.split_at_rank(2).mutation_count(), 0);
+        assert_eq!(a.weighted_merge(1, &a, 1).mutation_count(), 0);
     }
 }